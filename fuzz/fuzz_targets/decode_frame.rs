@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mcumgr_client::decode_frame;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_frame(data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mcumgr_client::read_serial_frame;
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = data;
+    let _ = read_serial_frame(&mut input);
+});
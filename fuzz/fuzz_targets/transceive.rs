@@ -0,0 +1,49 @@
+#![no_main]
+
+use anyhow::{format_err, Error};
+use libfuzzer_sys::fuzz_target;
+use mcumgr_client::{SerialSpecs, Session, SmpTransport};
+use std::time::Duration;
+
+/// Answers the first request with the fuzz input.
+struct FuzzTransport {
+    response: Option<Vec<u8>>,
+}
+
+impl SmpTransport for FuzzTransport {
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+        // take over the random sequence number, so the body gets decoded too
+        if let (Some(response), Some(seq)) = (self.response.as_mut(), frame.get(6)) {
+            if let Some(b) = response.get_mut(6) {
+                *b = *seq;
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        self.response.take().ok_or_else(|| format_err!("no answer"))
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let specs = SerialSpecs {
+        device: "fuzz".to_string(),
+        initial_timeout_s: 1,
+        subsequent_timeout_ms: 1,
+        nb_retry: 0,
+        linelength: 128,
+        mtu: 512,
+        baudrate: 115_200,
+        window: 1,
+    };
+    let transport = FuzzTransport {
+        response: Some(data.to_vec()),
+    };
+    let mut session = Session::with_transport(&specs, Box::new(transport));
+    let _ = session.list();
+});
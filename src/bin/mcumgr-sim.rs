@@ -0,0 +1,144 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use clap::{Parser, Subcommand};
+use log::{error, info, LevelFilter};
+use mcumgr_client::*;
+use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
+use std::io::Write;
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+/// Simulated MCUmgr device, for testing without hardware
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Verbose mode
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Largest request frame the device accepts, without the serial framing
+    #[arg(short, long, default_value_t = 1024)]
+    mtu: usize,
+
+    /// Maximum length of the serial lines of the answers
+    #[arg(short, long, default_value_t = 128)]
+    linelength: usize,
+
+    /// Size of each flash slot
+    #[arg(long, default_value_t = 1024 * 1024)]
+    slot_size: usize,
+
+    /// Number of images, each with a primary and a secondary slot
+    #[arg(long, default_value_t = 1)]
+    images: u8,
+
+    /// Delay before each answer
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "0s")]
+    latency: Duration,
+
+    /// Probability that a request gets lost, from 0 to 1
+    #[arg(long, default_value_t = 0.0)]
+    loss: f64,
+
+    /// Refuse SMP v2 requests, like a device with v2 disabled
+    #[arg(long)]
+    smp_v1: bool,
+
+    /// Firmware file to put into the primary slots at start
+    #[arg(short, long)]
+    firmware: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Serve a new pseudo terminal, its name is printed on start
+    Pty,
+
+    /// Serve a UDP socket, its address is printed on start
+    Udp {
+        /// Address to listen on
+        #[arg(default_value = "127.0.0.1:1337")]
+        address: String,
+    },
+}
+
+fn run(cli: &Cli) -> Result<(), Error> {
+    if !(0.0..=1.0).contains(&cli.loss) {
+        anyhow::bail!("loss must be between 0 and 1");
+    }
+    let mut device = SimDevice::new(SimOptions {
+        mtu: cli.mtu,
+        linelength: cli.linelength,
+        slot_size: cli.slot_size,
+        images: cli.images,
+        latency: cli.latency,
+        loss: cli.loss,
+        smp_v1_only: cli.smp_v1,
+    });
+    if let Some(firmware) = &cli.firmware {
+        for image in parse_images(firmware)? {
+            let image_index = image.image_index.unwrap_or(0);
+            info!("loading {} as image {}", image.file, image_index);
+            device.load(image_index, &image.data)?;
+        }
+    }
+
+    // the device name goes to stdout, so scripts can read it
+    match &cli.command {
+        Commands::Pty => serve_pty(&mut device),
+        Commands::Udp { address } => {
+            let socket = UdpSocket::bind(address)?;
+            println!("udp://{}", socket.local_addr()?);
+            std::io::stdout().flush()?;
+            device.serve_udp(&socket)
+        }
+    }
+}
+
+#[cfg(unix)]
+fn serve_pty(device: &mut SimDevice) -> Result<(), Error> {
+    use serialport::{SerialPort, TTYPort};
+
+    // the slave end stays open, so the master doesn't fail while no client is connected
+    let (mut master, slave) = TTYPort::pair()?;
+    let Some(name) = slave.name() else {
+        anyhow::bail!("pseudo terminal without name");
+    };
+    println!("{}", name);
+    std::io::stdout().flush()?;
+    master.set_timeout(Duration::from_secs(1))?;
+    device.serve_serial(&mut master)
+}
+
+#[cfg(not(unix))]
+fn serve_pty(_device: &mut SimDevice) -> Result<(), Error> {
+    anyhow::bail!("pseudo terminals are only supported on Unix")
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let level_filter = if cli.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    TermLogger::init(
+        level_filter,
+        Config::default(),
+        TerminalMode::Stderr,
+        ColorChoice::Auto,
+    )
+    .unwrap_or_else(|_| SimpleLogger::init(LevelFilter::Info, Default::default()).unwrap());
+
+    if let Err(e) = run(&cli) {
+        error!("Error: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::{debug, info};
+
+use crate::nmp_hdr::*;
+use crate::session::check_answer;
+use crate::session::check_rc;
+use crate::session::Session;
+use crate::transfer::is_timeout;
+
+/// Crash types known by the crash management group.
+pub const CRASH_TYPES: [&str; 5] = ["div0", "jump0", "ref0", "assert", "wdog"];
+
+impl Session {
+    /// Make the device crash, e.g. to test its fault handler.
+    ///
+    /// A crashing device usually does not answer, so a missing answer counts as success.
+    pub fn crash(&mut self, crash_type: &str) -> Result<(), Error> {
+        info!("send crash trigger request");
+
+        let req = CrashTriggerReq {
+            t: crash_type.to_string(),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        loop {
+            let seq_id = self.next_seq();
+            let (frame, request_header) = self.encode_request(
+                NmpOp::Write,
+                NmpGroup::Crash,
+                NmpIdCrash::Trigger,
+                &body,
+                seq_id,
+            )?;
+
+            // a slow device can still refuse the request, so wait as long as for any answer
+            self.set_initial_timeout()?;
+            let result = self.transceive(&frame, &request_header);
+
+            let (response_header, response_body) = match result {
+                Ok(ret) => ret,
+                Err(e) if is_timeout(&e) => {
+                    info!("no answer, device crashed");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+
+            // the device refused the request, or answered before crashing
+            check_answer(&request_header, &response_header)?;
+            if self.version_fallback(&request_header, &response_header, &response_body) {
+                continue;
+            }
+            debug!("{:?}", response_body);
+            return check_rc(&response_body);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{DeviceError, SmpError};
+    use crate::sim::SimOptions;
+    use crate::test_util::{sim_session, test_specs};
+
+    #[test]
+    fn crash_without_answer() {
+        for smp_v1_only in [false, true] {
+            let options = SimOptions {
+                smp_v1_only,
+                ..Default::default()
+            };
+            let mut session = sim_session(test_specs(), options);
+            session.crash("div0").unwrap();
+            let version = if smp_v1_only {
+                SMP_VERSION_1
+            } else {
+                SMP_VERSION_2
+            };
+            assert_eq!(session.smp_version(), version);
+        }
+    }
+
+    #[test]
+    fn crash_refused() {
+        let mut session = sim_session(test_specs(), SimOptions::default());
+        let e = session.crash("nothing").unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<SmpError>(),
+            Some(SmpError::Device(DeviceError::Mgmt(NmpErr::EInvalid)))
+        ));
+    }
+}
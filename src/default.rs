@@ -1,67 +1,165 @@
 // Copyright © 2023-2024 Vouch.io LLC
 
-use anyhow::{bail, Error, Result};
+use anyhow::{Error, Result};
 use log::debug;
 use log::info;
-use serde_cbor;
-use serde_json;
 
 use crate::nmp_hdr::*;
-use crate::transfer::encode_request;
-use crate::transfer::next_seq_id;
-use crate::transfer::open_port;
-use crate::transfer::transceive;
+use crate::session::check_rc;
+use crate::session::empty_body;
+use crate::session::Session;
 use crate::transfer::SerialSpecs;
 
-pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
-    info!("send reset request");
-
-    // open serial port
-    let mut port = open_port(specs)?;
-
-    // send request
-    let body = Vec::new();
-    let (data, request_header) = encode_request(
-        specs.linelength,
-        NmpOp::Write,
-        NmpGroup::Default,
-        NmpIdDef::Reset,
-        &body,
-        next_seq_id(),
-    )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
-    
-    // verify sequence id
-    if response_header.seq != request_header.seq {
-        bail!("wrong sequence number");
+impl Session {
+    pub fn reset(&mut self) -> Result<(), Error> {
+        info!("send reset request");
+
+        // send request
+        let body = Vec::new();
+        let (_, response_body) =
+            self.request(NmpOp::Write, NmpGroup::Default, NmpIdDef::Reset, &body)?;
+
+        // verify result code
+        debug!(
+            "response_body: {}",
+            serde_json::to_string_pretty(&response_body)?
+        );
+        check_rc(&response_body)?;
+        info!("reset complete");
+
+        Ok(())
+    }
+
+    /// Send a string to the device, which sends it back.
+    pub fn echo(&mut self, text: &str) -> Result<String, Error> {
+        info!("send echo request");
+
+        let req = EchoReq {
+            d: text.to_string(),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        let rsp: EchoRsp =
+            self.request_rsp(NmpOp::Write, NmpGroup::Default, NmpIdDef::Echo, &body)?;
+
+        Ok(rsp.r)
+    }
+
+    /// Enable or disable the echo of the device's console.
+    pub fn console_echo(&mut self, enable: bool) -> Result<(), Error> {
+        info!("send console echo control request");
+
+        let req = ConsEchoCtrlReq {
+            echo: enable as u32,
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        let (_, response_body) = self.request(
+            NmpOp::Write,
+            NmpGroup::Default,
+            NmpIdDef::ConsEchoCtrl,
+            &body,
+        )?;
+        check_rc(&response_body)
+    }
+
+    /// Read the statistics of all tasks (threads) of the device.
+    pub fn taskstat(&mut self) -> Result<TaskStatRsp, Error> {
+        info!("send task statistics request");
+
+        let body = empty_body()?;
+        self.request_rsp(NmpOp::Read, NmpGroup::Default, NmpIdDef::TaskStat, &body)
+    }
+
+    /// Read the statistics of the memory pools of the device.
+    pub fn mpstat(&mut self) -> Result<MpStatRsp, Error> {
+        info!("send memory pool statistics request");
+
+        let body = empty_body()?;
+        self.request_rsp(NmpOp::Read, NmpGroup::Default, NmpIdDef::MpStat, &body)
+    }
+
+    /// Read the date and time of the device.
+    pub fn datetime(&mut self) -> Result<String, Error> {
+        info!("send datetime read request");
+
+        let body = empty_body()?;
+        let rsp: DateTimeRsp =
+            self.request_rsp(NmpOp::Read, NmpGroup::Default, NmpIdDef::DateTimeStr, &body)?;
+
+        Ok(rsp.datetime)
     }
 
-    // verify response
-    if response_header.op != NmpOp::WriteRsp || response_header.group != NmpGroup::Default {
-        bail!("wrong response types");
+    /// Set the date and time of the device, formatted like `2024-01-31T12:00:00`.
+    pub fn set_datetime(&mut self, datetime: &str) -> Result<(), Error> {
+        info!("send datetime write request");
+
+        let req = DateTimeReq {
+            datetime: datetime.to_string(),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        let (_, response_body) = self.request(
+            NmpOp::Write,
+            NmpGroup::Default,
+            NmpIdDef::DateTimeStr,
+            &body,
+        )?;
+        check_rc(&response_body)
     }
 
-    // verify result code
-    debug!(
-        "response_body: {}",
-        serde_json::to_string_pretty(&response_body)?
-    );
-    if let serde_cbor::Value::Map(object) = response_body {
-        for (key, val) in object.iter() {
-            match key {
-                serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                    if let serde_cbor::Value::Integer(rc) = val {
-                        if *rc != 0 {
-                            bail!("rc = {}", rc);
-                        } else {
-                            info!("reset complete");
-                        }
-                    }
-                }
-                _ => (),
-            }
-        }
+    /// Read the size and number of the MCUmgr buffers of the device.
+    pub fn mcumgr_params(&mut self) -> Result<McumgrParamsRsp, Error> {
+        info!("send MCUmgr parameters request");
+
+        let body = empty_body()?;
+        self.request_rsp(
+            NmpOp::Read,
+            NmpGroup::Default,
+            NmpIdDef::McumgrParams,
+            &body,
+        )
+    }
+
+    /// Read the OS and application info, like `uname`. Without format, the device's default is used.
+    pub fn os_info(&mut self, format: Option<&str>) -> Result<String, Error> {
+        info!("send OS info request");
+
+        let req = OsInfoReq {
+            format: format.map(str::to_string),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        let rsp: OsInfoRsp =
+            self.request_rsp(NmpOp::Read, NmpGroup::Default, NmpIdDef::Info, &body)?;
+
+        Ok(rsp.output)
     }
+}
+
+pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
+    Session::open(specs)?.reset()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sim::SimOptions;
+    use crate::test_util::{sim_session, test_specs};
 
-    Ok(())
+    #[test]
+    fn os_commands() {
+        let mut session = sim_session(test_specs(), SimOptions::default());
+        assert_eq!(session.echo("hello").unwrap(), "hello");
+        session.console_echo(false).unwrap();
+
+        let tasks = session.taskstat().unwrap().tasks;
+        assert!(tasks.contains_key("main"));
+        let mpools = session.mpstat().unwrap().mpools;
+        assert_eq!(mpools["smp"].nblks, 4);
+
+        session.set_datetime("2024-01-31T12:00:00").unwrap();
+        assert_eq!(session.datetime().unwrap(), "2024-01-31T12:00:00");
+
+        let params = session.mcumgr_params().unwrap();
+        assert_eq!((params.buf_size, params.buf_count), (1024, 4));
+        assert_eq!(session.os_info(Some("sr")).unwrap(), "Zephyr 3.7.0");
+        assert!(session.os_info(Some("x")).is_err());
+        session.reset().unwrap();
+    }
 }
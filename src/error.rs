@@ -0,0 +1,334 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use serde_cbor::Value;
+use std::fmt;
+
+use crate::nmp_hdr::{NmpErr, NmpFsErr, NmpGroup, NmpImageErr};
+
+/// Errors of the SMP communication with a device.
+///
+/// The library functions return `anyhow::Error`, which can be downcast to this type to
+/// tell a missing or broken answer from an error the device reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    Transport(TransportError),
+    Device(DeviceError),
+}
+
+/// The device did not answer, or the answer could not be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportError {
+    Timeout,
+    BadCrc {
+        calculated: u16,
+        read: u16,
+    },
+    WrongLength {
+        expected: usize,
+        read: usize,
+    },
+    WrongSeq {
+        expected: u8,
+        read: u8,
+    },
+    /// The answer has the wrong op or group
+    WrongAnswer,
+    /// The frame is too short for the header, or the header is invalid
+    InvalidHeader,
+    /// The body of the frame is no valid CBOR
+    InvalidBody,
+}
+
+/// An error the device answered with.
+///
+/// SMP v1 devices answer with a top level `rc`, which is one of the generic MCUmgr codes.
+/// SMP v2 devices answer with `err: {group, rc}`, where the code belongs to the group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    Mgmt(NmpErr),
+    Image(NmpImageErr),
+    Fs(NmpFsErr),
+    /// A code without name, group is none for generic codes
+    Other {
+        group: Option<u16>,
+        rc: i64,
+    },
+}
+
+impl DeviceError {
+    pub fn new(group: Option<u16>, rc: i64) -> DeviceError {
+        let known = match group {
+            None => num::FromPrimitive::from_i64(rc).map(DeviceError::Mgmt),
+            Some(group) if group == NmpGroup::Image.to_u16() => {
+                num::FromPrimitive::from_i64(rc).map(DeviceError::Image)
+            }
+            Some(group) if group == NmpGroup::Fs.to_u16() => {
+                num::FromPrimitive::from_i64(rc).map(DeviceError::Fs)
+            }
+            Some(_) => None,
+        };
+        known.unwrap_or(DeviceError::Other { group, rc })
+    }
+
+    /// Read the error from a response body, if it reports one.
+    pub fn from_body(response_body: &Value) -> Option<DeviceError> {
+        let Value::Map(object) = response_body else {
+            return None;
+        };
+        let mut error = None;
+        for (key, val) in object.iter() {
+            match (key, val) {
+                (Value::Text(key), Value::Integer(rc)) if key == "rc" && *rc != 0 => {
+                    error = Some(DeviceError::new(None, *rc as i64));
+                }
+                (Value::Text(key), Value::Map(err)) if key == "err" => {
+                    let field = |name: &str| match err.get(&Value::Text(name.to_string())) {
+                        Some(Value::Integer(value)) => Some(*value),
+                        _ => None,
+                    };
+                    match (field("group"), field("rc")) {
+                        (Some(group), Some(rc)) if rc != 0 => {
+                            return Some(DeviceError::new(Some(group as u16), rc as i64));
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        error
+    }
+
+    /// Group of the code, none for generic codes.
+    pub fn group(&self) -> Option<u16> {
+        match self {
+            DeviceError::Mgmt(_) => None,
+            DeviceError::Image(_) => Some(NmpGroup::Image.to_u16()),
+            DeviceError::Fs(_) => Some(NmpGroup::Fs.to_u16()),
+            DeviceError::Other { group, .. } => *group,
+        }
+    }
+
+    pub fn rc(&self) -> i64 {
+        match self {
+            DeviceError::Mgmt(e) => *e as i64,
+            DeviceError::Image(e) => *e as i64,
+            DeviceError::Fs(e) => *e as i64,
+            DeviceError::Other { rc, .. } => *rc,
+        }
+    }
+}
+
+impl NmpErr {
+    pub fn message(&self) -> &'static str {
+        match self {
+            NmpErr::Ok => "no error",
+            NmpErr::EUnknown => "unknown error",
+            NmpErr::ENoMem => "out of memory",
+            NmpErr::EInvalid => "invalid argument",
+            NmpErr::ETimeout => "timeout",
+            NmpErr::ENoEnt => "no such entry",
+            NmpErr::EBadState => "bad state",
+            NmpErr::EMsgSize => "message too large",
+            NmpErr::ENotSup => "not supported",
+            NmpErr::ECorrupt => "corrupt data",
+            NmpErr::EBusy => "busy",
+            NmpErr::EAccessDenied => "access denied",
+            NmpErr::UnsupportedTooOld => "protocol version too old",
+            NmpErr::UnsupportedTooNew => "protocol version too new",
+            NmpErr::EPerUser => "user defined error",
+        }
+    }
+}
+
+impl NmpImageErr {
+    pub fn message(&self) -> &'static str {
+        match self {
+            NmpImageErr::Ok => "no error",
+            NmpImageErr::Unknown => "unknown error",
+            NmpImageErr::FlashConfigQueryFail => "flash area configuration query failed",
+            NmpImageErr::NoImage => "no image in slot",
+            NmpImageErr::NoTlvs => "image has no TLVs",
+            NmpImageErr::InvalidTlv => "image has an invalid TLV",
+            NmpImageErr::TlvMultipleHashesFound => "image has several hash TLVs",
+            NmpImageErr::TlvInvalidSize => "image has a TLV of invalid size",
+            NmpImageErr::HashNotFound => "image hash not found",
+            NmpImageErr::NoFreeSlot => "no free image slot",
+            NmpImageErr::FlashOpenFailed => "flash open failed",
+            NmpImageErr::FlashReadFailed => "flash read failed",
+            NmpImageErr::FlashWriteFailed => "flash write failed",
+            NmpImageErr::FlashEraseFailed => "flash erase failed",
+            NmpImageErr::InvalidSlot => "invalid image slot",
+            NmpImageErr::NoFreeMemory => "out of memory",
+            NmpImageErr::FlashContextAlreadySet => "flash context already set",
+            NmpImageErr::FlashContextNotSet => "flash context not set",
+            NmpImageErr::FlashAreaDeviceNull => "flash area device missing",
+            NmpImageErr::InvalidPageOffset => "invalid page offset",
+            NmpImageErr::InvalidOffset => "invalid offset",
+            NmpImageErr::InvalidLength => "invalid length",
+            NmpImageErr::InvalidImageHeader => "invalid image header",
+            NmpImageErr::InvalidImageHeaderMagic => "invalid image header magic",
+            NmpImageErr::InvalidHash => "invalid hash",
+            NmpImageErr::InvalidFlashAddress => "invalid flash address",
+            NmpImageErr::VersionGetFailed => "reading the image version failed",
+            NmpImageErr::CurrentVersionIsNewer => "running image is newer",
+            NmpImageErr::ImageAlreadyPending => "an image is already pending",
+            NmpImageErr::InvalidImageVectorTable => "invalid image vector table",
+            NmpImageErr::InvalidImageTooLarge => "image too large for the slot",
+            NmpImageErr::InvalidImageDataOverrun => "image data exceeds the announced length",
+            NmpImageErr::ImageConfirmationDenied => "image confirmation denied",
+            NmpImageErr::ImageSettingTestToActiveDenied => "the active image can't be tested",
+            NmpImageErr::ActiveSlotNotKnown => "active slot not known",
+        }
+    }
+}
+
+impl NmpFsErr {
+    pub fn message(&self) -> &'static str {
+        match self {
+            NmpFsErr::Ok => "no error",
+            NmpFsErr::Unknown => "unknown error",
+            NmpFsErr::FileInvalidName => "invalid file name",
+            NmpFsErr::FileNotFound => "file not found",
+            NmpFsErr::FileIsDirectory => "file is a directory",
+            NmpFsErr::FileOpenFailed => "file open failed",
+            NmpFsErr::FileSeekFailed => "file seek failed",
+            NmpFsErr::FileReadFailed => "file read failed",
+            NmpFsErr::FileTruncateFailed => "file truncate failed",
+            NmpFsErr::FileDeleteFailed => "file delete failed",
+            NmpFsErr::FileWriteFailed => "file write failed",
+            NmpFsErr::FileOffsetNotValid => "invalid file offset",
+            NmpFsErr::FileOffsetLargerThanFile => "offset larger than the file",
+            NmpFsErr::ChecksumHashNotFound => "hash or checksum type not supported",
+            NmpFsErr::MountPointNotFound => "mount point not found",
+            NmpFsErr::ReadOnlyFilesystem => "read only file system",
+            NmpFsErr::FileEmpty => "file is empty",
+        }
+    }
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmpError::Transport(e) => e.fmt(f),
+            SmpError::Device(e) => e.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Timeout => write!(f, "Operation timed out"),
+            TransportError::BadCrc { calculated, read } => write!(
+                f,
+                "wrong checksum, calculated: 0x{:04x}, read: 0x{:04x}",
+                calculated, read
+            ),
+            TransportError::WrongLength { expected, read } => write!(
+                f,
+                "wrong chunk length, expected: {}, read: {}",
+                expected, read
+            ),
+            TransportError::WrongSeq { expected, read } => write!(
+                f,
+                "wrong sequence number, expected: {}, read: {}",
+                expected, read
+            ),
+            TransportError::WrongAnswer => write!(f, "wrong answer types"),
+            TransportError::InvalidHeader => write!(f, "invalid SMP header"),
+            TransportError::InvalidBody => write!(f, "invalid CBOR body"),
+        }
+    }
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceError::Mgmt(e) => {
+                write!(f, "Error from device: {} (rc {})", e.message(), *e as u16)
+            }
+            DeviceError::Image(e) => write!(
+                f,
+                "Error from device: {} (image group, rc {})",
+                e.message(),
+                *e as u16
+            ),
+            DeviceError::Fs(e) => write!(
+                f,
+                "Error from device: {} (fs group, rc {})",
+                e.message(),
+                *e as u16
+            ),
+            DeviceError::Other {
+                group: Some(group),
+                rc,
+            } => write!(f, "Error from device: group {}, rc {}", group, rc),
+            DeviceError::Other { group: None, rc } => write!(f, "Error from device: {}", rc),
+        }
+    }
+}
+
+impl std::error::Error for SmpError {}
+
+impl From<TransportError> for SmpError {
+    fn from(e: TransportError) -> SmpError {
+        SmpError::Transport(e)
+    }
+}
+
+impl From<DeviceError> for SmpError {
+    fn from(e: DeviceError) -> SmpError {
+        SmpError::Device(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn body(entries: Vec<(&str, Value)>) -> Value {
+        Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, val)| (Value::Text(key.to_string()), val))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    }
+
+    #[test]
+    fn both_error_forms() {
+        let v1 = body(vec![("rc", Value::Integer(5))]);
+        assert_eq!(
+            DeviceError::from_body(&v1),
+            Some(DeviceError::Mgmt(NmpErr::ENoEnt))
+        );
+
+        let err = body(vec![
+            ("group", Value::Integer(1)),
+            ("rc", Value::Integer(12)),
+        ]);
+        let v2 = body(vec![("err", err)]);
+        let e = DeviceError::from_body(&v2).unwrap();
+        assert_eq!(e, DeviceError::Image(NmpImageErr::FlashWriteFailed));
+        assert_eq!((e.group(), e.rc()), (Some(1), 12));
+        assert_eq!(
+            e.to_string(),
+            "Error from device: flash write failed (image group, rc 12)"
+        );
+
+        assert_eq!(
+            DeviceError::new(Some(63), 2),
+            DeviceError::Other {
+                group: Some(63),
+                rc: 2
+            }
+        );
+        assert_eq!(
+            DeviceError::from_body(&body(vec![("rc", Value::Integer(0))])),
+            None
+        );
+        assert_eq!(DeviceError::from_body(&body(vec![])), None);
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, Context, Error, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use log::debug;
+use std::ffi::OsStr;
+use std::path::Path;
+
+use crate::mcuboot::IMAGE_MAGIC;
+
+/// Formats of firmware files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// Raw image bytes
+    Bin,
+    /// nRF Connect DFU package
+    Zip,
+    IntelHex,
+    Elf,
+    Srec,
+}
+
+impl FileFormat {
+    /// Detect the format from the file extension, or else from the content.
+    pub fn detect(filename: &Path, content: &[u8]) -> Result<FileFormat, Error> {
+        let extension = filename
+            .extension()
+            .and_then(OsStr::to_str)
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("bin") => return Ok(FileFormat::Bin),
+            Some("zip") => return Ok(FileFormat::Zip),
+            Some("hex") | Some("ihex") => return Ok(FileFormat::IntelHex),
+            Some("elf") | Some("axf") => return Ok(FileFormat::Elf),
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => {
+                return Ok(FileFormat::Srec)
+            }
+            _ => {}
+        }
+
+        let text = content.trim_ascii_start();
+        if content.starts_with(b"\x7fELF") {
+            Ok(FileFormat::Elf)
+        } else if content.starts_with(b"PK\x03\x04") {
+            Ok(FileFormat::Zip)
+        } else if content.len() >= 4 && LittleEndian::read_u32(content) == IMAGE_MAGIC {
+            Ok(FileFormat::Bin)
+        } else if text.starts_with(b":") {
+            Ok(FileFormat::IntelHex)
+        } else if text.len() >= 2 && text[0] == b'S' && text[1].is_ascii_digit() {
+            Ok(FileFormat::Srec)
+        } else {
+            bail!(
+                "unknown file format of {}, supported are .bin, .zip, .hex, .elf and .srec",
+                filename.to_string_lossy()
+            );
+        }
+    }
+}
+
+/// Flatten the image bytes of an Intel HEX file.
+pub fn parse_ihex(content: &[u8]) -> Result<Vec<u8>, Error> {
+    let text = std::str::from_utf8(content).context("Intel HEX file is not text")?;
+    let mut records = Vec::new();
+    let mut base: u64 = 0;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(record) = line.strip_prefix(':') else {
+            bail!("line {}: missing start code", number + 1);
+        };
+        let bytes = hex::decode(record).with_context(|| format!("line {}", number + 1))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            bail!("line {}: wrong record length", number + 1);
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            bail!("line {}: wrong checksum", number + 1);
+        }
+        let address = BigEndian::read_u16(&bytes[1..3]) as u64;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => records.push((base + address, data.to_vec())),
+            0x01 => break,
+            0x02 if data.len() == 2 => base = (BigEndian::read_u16(data) as u64) << 4,
+            0x04 if data.len() == 2 => base = (BigEndian::read_u16(data) as u64) << 16,
+            // start addresses don't matter for the image
+            0x03 | 0x05 => {}
+            other => bail!("line {}: unsupported record type {}", number + 1, other),
+        }
+    }
+    flatten(records)
+}
+
+/// Flatten the image bytes of a Motorola S-record file.
+pub fn parse_srec(content: &[u8]) -> Result<Vec<u8>, Error> {
+    let text = std::str::from_utf8(content).context("S-record file is not text")?;
+    let mut records = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        // bytes, as a non-ASCII character is no valid record but must not split a slice
+        let line = line.trim().as_bytes();
+        if line.is_empty() {
+            continue;
+        }
+        if line.len() < 2 || line[0] != b'S' {
+            bail!("line {}: missing start code", number + 1);
+        }
+        let bytes = hex::decode(&line[2..]).with_context(|| format!("line {}", number + 1))?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            bail!("line {}: wrong record length", number + 1);
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+            bail!("line {}: wrong checksum", number + 1);
+        }
+        let address_len = match line[1] {
+            b'1' => 2,
+            b'2' => 3,
+            b'3' => 4,
+            // header, record counts and start addresses
+            b'0' | b'5'..=b'9' => continue,
+            other => bail!(
+                "line {}: unsupported record type S{}",
+                number + 1,
+                other as char
+            ),
+        };
+        if bytes.len() < address_len + 2 {
+            bail!("line {}: wrong record length", number + 1);
+        }
+        let address = BigEndian::read_uint(&bytes[1..1 + address_len], address_len);
+        records.push((address, bytes[1 + address_len..bytes.len() - 1].to_vec()));
+    }
+    flatten(records)
+}
+
+/// Flatten the loadable segments of an ELF file, at their physical (load) addresses.
+pub fn parse_elf(content: &[u8]) -> Result<Vec<u8>, Error> {
+    if content.len() < 52 || !content.starts_with(b"\x7fELF") {
+        bail!("not an ELF file");
+    }
+    let is_64 = match content[4] {
+        1 => false,
+        2 => true,
+        _ => bail!("unknown ELF class"),
+    };
+    match content[5] {
+        1 => parse_elf_segments::<LittleEndian>(content, is_64),
+        2 => parse_elf_segments::<BigEndian>(content, is_64),
+        _ => bail!("unknown ELF byte order"),
+    }
+}
+
+fn parse_elf_segments<B: ByteOrder>(content: &[u8], is_64: bool) -> Result<Vec<u8>, Error> {
+    const PT_LOAD: u32 = 1;
+
+    let (phoff, phentsize, phnum) = if is_64 {
+        if content.len() < 64 {
+            bail!("ELF header truncated");
+        }
+        (
+            B::read_u64(&content[32..40]),
+            B::read_u16(&content[54..56]),
+            B::read_u16(&content[56..58]),
+        )
+    } else {
+        (
+            B::read_u32(&content[28..32]) as u64,
+            B::read_u16(&content[42..44]),
+            B::read_u16(&content[44..46]),
+        )
+    };
+
+    let mut records = Vec::new();
+    for i in 0..phnum as u64 {
+        let Some(start) = i
+            .checked_mul(phentsize as u64)
+            .and_then(|off| phoff.checked_add(off))
+        else {
+            bail!("ELF program header out of range");
+        };
+        let Some(ph) = file_range(content, start, phentsize as u64) else {
+            bail!("ELF program header truncated");
+        };
+        let (p_type, offset, paddr, filesz) = if is_64 {
+            if ph.len() < 56 {
+                bail!("ELF program header truncated");
+            }
+            (
+                B::read_u32(&ph[0..4]),
+                B::read_u64(&ph[8..16]),
+                B::read_u64(&ph[24..32]),
+                B::read_u64(&ph[32..40]),
+            )
+        } else {
+            if ph.len() < 32 {
+                bail!("ELF program header truncated");
+            }
+            (
+                B::read_u32(&ph[0..4]),
+                B::read_u32(&ph[4..8]) as u64,
+                B::read_u32(&ph[12..16]) as u64,
+                B::read_u32(&ph[16..20]) as u64,
+            )
+        };
+        // segments without file content, like .bss, are not part of the image
+        if p_type != PT_LOAD || filesz == 0 {
+            continue;
+        }
+        let Some(data) = file_range(content, offset, filesz) else {
+            bail!("ELF segment exceeds the file");
+        };
+        if paddr.checked_add(filesz).is_none() {
+            bail!("ELF segment exceeds the address space");
+        }
+        records.push((paddr, data.to_vec()));
+    }
+    flatten(records)
+}
+
+/// The `len` bytes at `offset` of the file, none if they exceed it.
+fn file_range(content: &[u8], offset: u64, len: u64) -> Option<&[u8]> {
+    let start = usize::try_from(offset).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    content.get(start..end)
+}
+
+/// Join the records to one contiguous image, failing on overlaps, gaps and missing data.
+fn flatten(mut records: Vec<(u64, Vec<u8>)>) -> Result<Vec<u8>, Error> {
+    records.sort_by_key(|(address, _)| *address);
+
+    let mut segments: Vec<(u64, Vec<u8>)> = Vec::new();
+    for (address, data) in records {
+        match segments.last_mut() {
+            Some((start, image)) if *start + image.len() as u64 == address => {
+                image.extend_from_slice(&data)
+            }
+            Some((start, image)) if *start + image.len() as u64 > address => {
+                bail!("overlapping data at 0x{:08x}", address)
+            }
+            _ => segments.push((address, data)),
+        }
+    }
+
+    match segments.len() {
+        0 => bail!("file contains no data"),
+        1 => {
+            let (address, image) = segments.remove(0);
+            debug!("image of {} bytes at 0x{:08x}", image.len(), address);
+            Ok(image)
+        }
+        _ => {
+            let ranges: Vec<String> = segments
+                .iter()
+                .map(|(address, image)| {
+                    format!("0x{:08x}-0x{:08x}", address, address + image.len() as u64)
+                })
+                .collect();
+            bail!(
+                "file contains {} segments with gaps: {}",
+                segments.len(),
+                ranges.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn ihex_with_extended_address() {
+        let hex = ":020000040001F9\n\
+                   :0400000001020304F2\n\
+                   :02000400AABB95\n\
+                   :00000001FF\n";
+        assert_eq!(
+            parse_ihex(hex.as_bytes()).unwrap(),
+            vec![1, 2, 3, 4, 0xaa, 0xbb]
+        );
+        assert!(parse_ihex(b":0400000001020304F3\n").is_err());
+    }
+
+    #[test]
+    fn srec_records() {
+        let srec = "S00600004844521B\n\
+                    S3090800000001020304E4\n\
+                    S30708000004AABB87\n\
+                    S70508000000F2\n";
+        assert_eq!(
+            parse_srec(srec.as_bytes()).unwrap(),
+            vec![1, 2, 3, 4, 0xaa, 0xbb]
+        );
+
+        // non-ASCII characters are errors, not panics
+        assert!(parse_srec("S\u{e9}0600\n".as_bytes()).is_err());
+        assert!(parse_srec("S3\u{e9}\n".as_bytes()).is_err());
+        assert!(parse_srec("\u{e9}S\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn elf_load_segments() {
+        // ELF32 little endian with one loadable segment and one .bss like segment
+        let mut elf = vec![0u8; 52 + 2 * 32];
+        elf[..7].copy_from_slice(b"\x7fELF\x01\x01\x01");
+        LittleEndian::write_u32(&mut elf[28..32], 52);
+        LittleEndian::write_u16(&mut elf[42..44], 32);
+        LittleEndian::write_u16(&mut elf[44..46], 2);
+        for (i, (offset, paddr, filesz)) in
+            [(116, 0x1000, 4), (0, 0x2000_0000, 0)].iter().enumerate()
+        {
+            let ph = &mut elf[52 + i * 32..52 + (i + 1) * 32];
+            LittleEndian::write_u32(&mut ph[0..4], 1);
+            LittleEndian::write_u32(&mut ph[4..8], *offset);
+            LittleEndian::write_u32(&mut ph[12..16], *paddr);
+            LittleEndian::write_u32(&mut ph[16..20], *filesz);
+        }
+        elf.extend_from_slice(&[5, 6, 7, 8]);
+        assert_eq!(parse_elf(&elf).unwrap(), vec![5, 6, 7, 8]);
+
+        // offsets and sizes which overflow are errors, not panics
+        let mut bad = elf.clone();
+        LittleEndian::write_u32(&mut bad[52 + 16..52 + 20], u32::MAX);
+        assert!(parse_elf(&bad).is_err());
+        let mut bad64 = vec![0u8; 64];
+        bad64[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        LittleEndian::write_u64(&mut bad64[32..40], u64::MAX);
+        LittleEndian::write_u16(&mut bad64[54..56], 56);
+        LittleEndian::write_u16(&mut bad64[56..58], 2);
+        assert!(parse_elf(&bad64).is_err());
+        let mut ph = vec![0u8; 56];
+        LittleEndian::write_u32(&mut ph[0..4], 1);
+        LittleEndian::write_u64(&mut ph[8..16], u64::MAX);
+        LittleEndian::write_u64(&mut ph[32..40], 4);
+        LittleEndian::write_u64(&mut bad64[32..40], 64);
+        LittleEndian::write_u16(&mut bad64[56..58], 1);
+        bad64.extend_from_slice(&ph);
+        assert!(parse_elf(&bad64).is_err());
+    }
+
+    #[test]
+    fn reject_gaps() {
+        let err = flatten(vec![(0, vec![1, 2]), (4, vec![3])]).unwrap_err();
+        assert!(err.to_string().contains("2 segments"));
+        assert!(flatten(vec![(0, vec![1, 2]), (1, vec![3])]).is_err());
+    }
+
+    #[test]
+    fn detect_format() {
+        let detect = |name: &str, content: &[u8]| FileFormat::detect(&PathBuf::from(name), content);
+        assert_eq!(detect("a.HEX", b"").unwrap(), FileFormat::IntelHex);
+        assert_eq!(
+            detect("zephyr.signed", b":00000001FF").unwrap(),
+            FileFormat::IntelHex
+        );
+        assert_eq!(
+            detect("image", b"S00600004844521B").unwrap(),
+            FileFormat::Srec
+        );
+        assert_eq!(detect("zephyr", b"\x7fELF").unwrap(), FileFormat::Elf);
+        assert_eq!(
+            detect("image", &[0x3d, 0xb8, 0xf3, 0x96]).unwrap(),
+            FileFormat::Bin
+        );
+        assert!(detect("image", b"garbage").is_err());
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, Error, Result};
+use log::{debug, info};
+use std::cmp::min;
+use std::fs::{read, write};
+use std::path::Path;
+
+use crate::nmp_hdr::*;
+use crate::session::check_rc;
+use crate::session::decode_rsp;
+use crate::session::empty_body;
+use crate::session::Session;
+
+impl Session {
+    /// Upload a local file to the file system of the device.
+    pub fn fs_upload<F>(
+        &mut self,
+        local: &Path,
+        remote: &str,
+        mut progress: Option<F>,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        info!("uploading {} to {}", local.to_string_lossy(), remote);
+
+        let data = read(local)?;
+
+        self.with_initial_timeout(|session| {
+            // transfer in blocks, an empty file still needs one request to create it
+            let mut off: usize = 0;
+            loop {
+                let seq_id = session.next_seq();
+                let max_len = min(session.specs.mtu, data.len() - off);
+                let (frame, request_header, _) = session.chunk_frame(
+                    NmpOp::Write,
+                    NmpGroup::Fs,
+                    NmpIdFs::File,
+                    seq_id,
+                    max_len,
+                    |try_length| {
+                        let req = FsUploadReq {
+                            name: remote.to_string(),
+                            off: off as u64,
+                            data: data[off..off + try_length].to_vec(),
+                            len: if off == 0 {
+                                Some(data.len() as u64)
+                            } else {
+                                None
+                            },
+                        };
+                        Ok(serde_cbor::to_vec(&req)?)
+                    },
+                )?;
+
+                let response_body = session.transceive_retry(&frame, &request_header)?;
+                debug!("{:?}", response_body);
+                let rsp: FsUploadRsp = decode_rsp(response_body)?;
+
+                let off_start = off;
+                off = rsp.off as usize;
+                if off > data.len() || (off == off_start && !data.is_empty()) {
+                    bail!("wrong offset received");
+                }
+
+                if let Some(ref mut f) = progress {
+                    f(off as u64, data.len() as u64);
+                }
+
+                if off == data.len() {
+                    break;
+                }
+
+                session.set_subsequent_timeout()?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Download a file from the file system of the device to a local file.
+    pub fn fs_download<F>(
+        &mut self,
+        remote: &str,
+        local: &Path,
+        mut progress: Option<F>,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        info!("downloading {} to {}", remote, local.to_string_lossy());
+
+        let data = self.with_initial_timeout(|session| {
+            let mut data: Vec<u8> = Vec::new();
+            let mut total: Option<u64> = None;
+            loop {
+                let req = FsDownloadReq {
+                    name: remote.to_string(),
+                    off: data.len() as u64,
+                };
+                let body = serde_cbor::to_vec(&req)?;
+                let seq_id = session.next_seq();
+                let (frame, request_header) = session.encode_request(
+                    NmpOp::Read,
+                    NmpGroup::Fs,
+                    NmpIdFs::File,
+                    &body,
+                    seq_id,
+                )?;
+
+                let response_body = session.transceive_retry(&frame, &request_header)?;
+                let rsp: FsDownloadRsp = decode_rsp(response_body)?;
+                debug!("received {} bytes at offset {}", rsp.data.len(), rsp.off);
+
+                if rsp.off != data.len() as u64 {
+                    bail!("wrong offset received");
+                }
+                if total.is_none() {
+                    total = rsp.len;
+                }
+                let total = match total {
+                    Some(total) => total,
+                    None => bail!("file length missing in answer"),
+                };
+                data.extend_from_slice(&rsp.data);
+
+                if let Some(ref mut f) = progress {
+                    f(data.len() as u64, total);
+                }
+
+                if data.len() as u64 >= total || rsp.data.is_empty() {
+                    break;
+                }
+
+                session.set_subsequent_timeout()?;
+            }
+
+            Ok(data)
+        })?;
+
+        write(local, &data)?;
+        Ok(())
+    }
+
+    /// Read the length of a file on the device.
+    pub fn fs_stat(&mut self, remote: &str) -> Result<FsStatusRsp, Error> {
+        info!("send file status request");
+
+        let req = FsStatusReq {
+            name: remote.to_string(),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        self.request_rsp(NmpOp::Read, NmpGroup::Fs, NmpIdFs::Status, &body)
+    }
+
+    /// Calculate a hash or checksum of a file on the device, like "crc32" or "sha256".
+    /// Without type, the device's default is used.
+    pub fn fs_hash(&mut self, remote: &str, hash_type: Option<&str>) -> Result<FsHashRsp, Error> {
+        info!("send file hash request");
+
+        let req = FsHashReq {
+            name: remote.to_string(),
+            hash_type: hash_type.map(str::to_string),
+            off: None,
+            len: None,
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        self.request_rsp(NmpOp::Read, NmpGroup::Fs, NmpIdFs::HashChecksum, &body)
+    }
+
+    /// Close all files the file system management of the device holds open.
+    pub fn fs_close(&mut self) -> Result<(), Error> {
+        info!("send file close request");
+
+        let body = empty_body()?;
+        let (_, response_body) = self.request(NmpOp::Write, NmpGroup::Fs, NmpIdFs::Close, &body)?;
+        check_rc(&response_body)
+    }
+}
+
+impl FsHashRsp {
+    /// The hash or checksum as hex string.
+    pub fn output_hex(&self) -> String {
+        match &self.output {
+            serde_cbor::Value::Bytes(bytes) => hex::encode(bytes),
+            serde_cbor::Value::Integer(checksum) => format!("{:08x}", checksum),
+            other => format!("{:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sim::SimOptions;
+    use crate::test_util::{sim_session, temp_file, test_specs};
+    use sha2::{Digest, Sha256};
+    use std::fs::read;
+
+    #[test]
+    fn fs_roundtrip() {
+        let mut session = sim_session(test_specs(), SimOptions::default());
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 253) as u8).collect();
+        for (name, content) in [("fs-empty.bin", &[][..]), ("fs-data.bin", &content)] {
+            let local = temp_file(name, content);
+            let remote = format!("/lfs/{}", name);
+            let mut uploaded = 0;
+            session
+                .fs_upload(&local, &remote, Some(|off, _| uploaded = off))
+                .unwrap();
+            assert_eq!(uploaded, content.len() as u64);
+            assert_eq!(session.fs_stat(&remote).unwrap().len, content.len() as u64);
+
+            let copy = temp_file(&format!("copy-{}", name), &[]);
+            session
+                .fs_download(&remote, &copy, None::<fn(u64, u64)>)
+                .unwrap();
+            assert_eq!(read(&copy).unwrap(), content);
+        }
+        session.fs_close().unwrap();
+        let copy = temp_file("copy-missing.bin", &[]);
+        assert!(session
+            .fs_download("/lfs/missing", &copy, None::<fn(u64, u64)>)
+            .is_err());
+    }
+
+    #[test]
+    fn fs_hash() {
+        let mut session = sim_session(test_specs(), SimOptions::default());
+        let local = temp_file("fs-hash.bin", b"123456789");
+        session
+            .fs_upload(&local, "/lfs/hash.bin", None::<fn(u64, u64)>)
+            .unwrap();
+
+        let rsp = session.fs_hash("/lfs/hash.bin", None).unwrap();
+        assert_eq!((rsp.hash_type.as_str(), rsp.len), ("crc32", 9));
+        assert_eq!(rsp.output_hex(), "cbf43926");
+        let rsp = session.fs_hash("/lfs/hash.bin", Some("sha256")).unwrap();
+        assert_eq!(rsp.output_hex(), hex::encode(Sha256::digest(b"123456789")));
+        assert!(session.fs_hash("/lfs/hash.bin", Some("md5")).is_err());
+    }
+}
@@ -4,358 +4,1271 @@ use anyhow::{bail, Error, Result};
 use humantime::format_duration;
 use log::{debug, info, warn};
 use serde::Deserialize;
-use serde_cbor;
-use serde_json;
 use sha2::{Digest, Sha256};
-use std::ffi::OsStr;
-use std::fs::read;
-use std::io::Read;
-use std::path::PathBuf;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::fs::{read, write};
+use std::io::{Cursor, Read};
+use std::path::Path;
 use std::time::Duration;
 use std::time::Instant;
 
+use crate::error::{DeviceError, SmpError};
+use crate::firmware::{parse_elf, parse_ihex, parse_srec, FileFormat};
+use crate::mcuboot::image_hash;
+use crate::mcuboot::ImageHeader;
 use crate::nmp_hdr::*;
-use crate::transfer::encode_request;
-use crate::transfer::next_seq_id;
-use crate::transfer::open_port;
-use crate::transfer::transceive;
+use crate::session::check_answer;
+use crate::session::check_rc;
+use crate::session::decode_rsp;
+use crate::session::empty_body;
+use crate::session::Session;
+use crate::transfer::decode_frame;
+use crate::transfer::is_timeout;
 use crate::transfer::SerialSpecs;
 
-fn get_rc(response_body: &serde_cbor::Value) -> Option<u32> {
-    let mut rc: Option<u32> = None;
-    if let serde_cbor::Value::Map(object) = response_body {
-        for (key, val) in object.iter() {
-            match key {
-                serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                    if let serde_cbor::Value::Integer(parsed_rc) = val {
-                        rc = Some(*parsed_rc as u32);
-                    }
-                }
-                _ => (),
-            }
-        }
+#[derive(Debug, Deserialize)]
+struct ManifestFile {
+    file: String,
+    #[serde(default, deserialize_with = "number_or_string")]
+    image_index: Option<u64>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    slot: Option<u64>,
+    load_address: Option<u64>,
+    #[serde(rename = "version_MCUBOOT")]
+    version_mcuboot: Option<String>,
+    board: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    files: Vec<ManifestFile>,
+}
+
+// the nRF Connect SDK writes some numbers of the manifest as strings
+fn number_or_string<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u64),
+        String(String),
+    }
+    match Option::<NumberOrString>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(NumberOrString::Number(n)) => Ok(Some(n)),
+        Some(NumberOrString::String(s)) => s.parse().map(Some).map_err(serde::de::Error::custom),
     }
-    rc
 }
 
-fn check_answer(request_header: &NmpHdr, response_header: &NmpHdr) -> bool {
-    // verify sequence id
-    if response_header.seq != request_header.seq {
-        log::debug!("wrong sequence number");
-        return false;
+/// An image of a firmware file. A nRF Connect DFU package holds one per core or image.
+#[derive(Debug, Clone)]
+pub struct FirmwareImage {
+    /// Name of the image file in the package
+    pub file: String,
+    /// Image number to upload to, given by the package manifest
+    pub image_index: Option<u8>,
+    pub slot: Option<u64>,
+    pub load_address: Option<u64>,
+    pub version: Option<String>,
+    pub board: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl FirmwareImage {
+    fn single(filename: &Path, data: Vec<u8>) -> FirmwareImage {
+        FirmwareImage {
+            file: filename.to_string_lossy().to_string(),
+            image_index: None,
+            slot: None,
+            load_address: None,
+            version: None,
+            board: None,
+            data,
+        }
     }
+}
 
-    let expected_op_type = match request_header.op {
-        NmpOp::Read => NmpOp::ReadRsp,
-        NmpOp::Write => NmpOp::WriteRsp,
-        _ => return false,
+/// Read all images of a firmware file, in any of the supported formats.
+pub fn parse_images(filename: &Path) -> Result<Vec<FirmwareImage>, Error> {
+    let content = read(filename)?;
+    let data = match FileFormat::detect(filename, &content)? {
+        FileFormat::Bin => content,
+        FileFormat::Zip => return parse_zip(content),
+        FileFormat::IntelHex => parse_ihex(&content)?,
+        FileFormat::Elf => parse_elf(&content)?,
+        FileFormat::Srec => parse_srec(&content)?,
     };
+    Ok(vec![FirmwareImage::single(filename, data)])
+}
 
-    // verify response
-    if response_header.op != expected_op_type || response_header.group != request_header.group {
-        log::debug!("wrong response types");
-        return false;
+fn parse_zip(content: Vec<u8>) -> Result<Vec<FirmwareImage>, Error> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(content))?;
+    let file = archive.by_name("manifest.json")?;
+    let manifest: Manifest = serde_json::from_reader(file)?;
+    if manifest.files.is_empty() {
+        bail!("no images in the package manifest");
     }
 
-    true
+    let mut images = Vec::new();
+    for entry in manifest.files {
+        let image_index = match entry.image_index {
+            Some(index) => match u8::try_from(index) {
+                Ok(index) => Some(index),
+                Err(_) => bail!("image index {} of {} out of range", index, entry.file),
+            },
+            None => None,
+        };
+        let mut file = archive.by_name(&entry.file)?;
+        let mut data: Vec<u8> = Vec::new();
+        file.read_to_end(&mut data)?;
+        images.push(FirmwareImage {
+            file: entry.file,
+            image_index,
+            slot: entry.slot,
+            load_address: entry.load_address,
+            version: entry.version_mcuboot,
+            board: entry.board,
+            data,
+        });
+    }
+    Ok(images)
 }
 
-pub fn erase(specs: &SerialSpecs, slot: Option<u32>) -> Result<(), Error> {
-    info!("erase request");
+/// Read the image bytes of a firmware file which holds a single image.
+pub fn parse_data(filename: &Path) -> Result<Vec<u8>, Error> {
+    let mut images = parse_images(filename)?;
+    if images.len() > 1 {
+        bail!(
+            "{} holds {} images, only one is supported here",
+            filename.to_string_lossy(),
+            images.len()
+        );
+    }
+    Ok(images.remove(0).data)
+}
 
-    // open serial port
-    let mut port = open_port(specs)?;
+/// Options of an image upload.
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// Image number to upload to
+    pub slot: u8,
+    /// Continue an interrupted upload at the offset the device already holds
+    pub resume: bool,
+    /// Upload the file even if it is not an MCUboot image
+    pub force: bool,
+    /// Don't upload if the image is already active or pending on the device
+    pub skip_if_present: bool,
+    /// Image numbers to upload from a multi-image package, all if empty
+    pub images: Vec<u8>,
+}
 
-    let req = ImageEraseReq { slot };
-    let body = serde_cbor::to_vec(&req)?;
-    // send request
-    let (data, request_header) = encode_request(
-        specs.linelength,
-        NmpOp::Write,
-        NmpGroup::Image,
-        NmpIdImage::Erase,
-        &body,
-        next_seq_id(),
-    )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
+/// How `flash` marks the new image for the next boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlashMode {
+    /// Boot the image for test only, the bootloader reverts it on the next reset
+    Test,
+    /// Boot the image for test and confirm it once it runs
+    #[default]
+    Confirm,
+    /// Mark the image permanent before the reset
+    Permanent,
+}
 
-    if !check_answer(&request_header, &response_header) {
-        bail!("wrong answer types")
-    }
+/// Options of a complete firmware update.
+#[derive(Debug, Clone)]
+pub struct FlashOptions {
+    pub upload: UploadOptions,
+    pub mode: FlashMode,
+    /// How long to wait for the device to answer again after the reset
+    pub timeout: Duration,
+}
 
-    if let Some(rc) = get_rc(&response_body) {
-        if rc != 0 {
-            bail!("Error from device: {}", rc);
-        }
+impl Session {
+    pub fn erase(&mut self, slot: Option<u32>) -> Result<(), Error> {
+        info!("erase request");
+
+        let req = ImageEraseReq { slot };
+        let body = serde_cbor::to_vec(&req)?;
+        // send request
+        let (_, response_body) =
+            self.request(NmpOp::Write, NmpGroup::Image, NmpIdImage::Erase, &body)?;
+
+        check_rc(&response_body)?;
+
+        log::debug!("{:?}", response_body);
+        Ok(())
     }
 
-    log::debug!("{:?}", response_body);
-    Ok(())
-}
+    pub fn test(&mut self, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error> {
+        info!("set image pending request");
 
-pub fn test(specs: &SerialSpecs, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error> {
-    info!("set image pending request");
+        let req = ImageStateReq { hash, confirm };
+        let body = serde_cbor::to_vec(&req)?;
+        // send request
+        let (_, response_body) =
+            self.request(NmpOp::Write, NmpGroup::Image, NmpIdImage::State, &body)?;
+
+        check_rc(&response_body)?;
+
+        log::debug!("{:?}", response_body);
+        Ok(())
+    }
 
-    // open serial port
-    let mut port = open_port(specs)?;
+    /// Find the image with this hash, if it is active or pending in the given image number.
+    pub fn find_image(
+        &mut self,
+        hash: &[u8],
+        image: u32,
+    ) -> Result<Option<ImageStateEntry>, Error> {
+        let state = self.list()?;
+        Ok(state
+            .images
+            .into_iter()
+            .find(|e| e.image == image && e.hash == hash && (e.active || e.pending)))
+    }
 
-    let req = ImageStateReq { hash, confirm };
-    let body = serde_cbor::to_vec(&req)?;
-    // send request
-    let (data, request_header) = encode_request(
-        specs.linelength,
-        NmpOp::Write,
-        NmpGroup::Image,
-        NmpIdImage::State,
-        &body,
-        next_seq_id(),
-    )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
+    pub fn list(&mut self) -> Result<ImageStateRsp, Error> {
+        info!("send image list request");
 
-    if !check_answer(&request_header, &response_header) {
-        bail!("wrong answer types")
+        // send request
+        let body = empty_body()?;
+        self.request_rsp(NmpOp::Read, NmpGroup::Image, NmpIdImage::State, &body)
     }
 
-    if let Some(rc) = get_rc(&response_body) {
-        if rc != 0 {
-            return Err(anyhow::format_err!("Error from device: {}", rc));
+    /// Check whether the device holds a core dump.
+    pub fn core_list(&mut self) -> Result<bool, Error> {
+        info!("send core list request");
+
+        let body = empty_body()?;
+        let (_, response_body) =
+            self.request(NmpOp::Read, NmpGroup::Image, NmpIdImage::CoreList, &body)?;
+
+        // no entry means no core dump
+        match DeviceError::from_body(&response_body) {
+            None => Ok(true),
+            Some(DeviceError::Mgmt(NmpErr::ENoEnt)) => Ok(false),
+            Some(e) => Err(SmpError::Device(e).into()),
         }
     }
 
-    log::debug!("{:?}", response_body);
-    Ok(())
-}
+    /// Download the core dump of the device to a file.
+    pub fn core_download<F>(
+        &mut self,
+        filename: &Path,
+        mut progress: Option<F>,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        info!("downloading core dump to {}", filename.to_string_lossy());
 
-pub fn list(specs: &SerialSpecs) -> Result<ImageStateRsp, Error> {
-    info!("send image list request");
+        let data = self.with_initial_timeout(|session| {
+            let mut data: Vec<u8> = Vec::new();
+            let mut total: Option<u32> = None;
+            loop {
+                let req = CoreLoadReq {
+                    off: data.len() as u32,
+                };
+                let body = serde_cbor::to_vec(&req)?;
+                let seq_id = session.next_seq();
+                let (frame, request_header) = session.encode_request(
+                    NmpOp::Read,
+                    NmpGroup::Image,
+                    NmpIdImage::CoreLoad,
+                    &body,
+                    seq_id,
+                )?;
 
-    // open serial port
-    let mut port = open_port(specs)?;
+                let response_body = session.transceive_retry(&frame, &request_header)?;
+                let rsp: CoreLoadRsp = decode_rsp(response_body)?;
+                debug!("received {} bytes at offset {}", rsp.data.len(), rsp.off);
 
-    // send request
-    let body: Vec<u8> =
-        serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
-    let (data, request_header) = encode_request(
-        specs.linelength,
-        NmpOp::Read,
-        NmpGroup::Image,
-        NmpIdImage::State,
-        &body,
-        next_seq_id(),
-    )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
+                if rsp.off != data.len() as u32 {
+                    bail!("wrong offset received");
+                }
+                if total.is_none() {
+                    total = rsp.len;
+                }
+                let Some(total) = total else {
+                    bail!("core dump length missing in answer");
+                };
+                data.extend_from_slice(&rsp.data);
+
+                if let Some(ref mut f) = progress {
+                    f(data.len() as u64, total as u64);
+                }
+
+                if data.len() as u32 >= total || rsp.data.is_empty() {
+                    break;
+                }
+
+                session.set_subsequent_timeout()?;
+            }
 
-    if !check_answer(&request_header, &response_header) {
-        bail!("wrong answer types")
+            Ok(data)
+        })?;
+
+        write(filename, &data)?;
+        Ok(())
     }
 
-    let ans: ImageStateRsp = serde_cbor::value::from_value(response_body)
-        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+    /// Erase the core dump of the device.
+    pub fn core_erase(&mut self) -> Result<(), Error> {
+        info!("send core erase request");
 
-    Ok(ans)
-}
+        let body = empty_body()?;
+        let (_, response_body) =
+            self.request(NmpOp::Write, NmpGroup::Image, NmpIdImage::CoreLoad, &body)?;
+        check_rc(&response_body)
+    }
 
-#[derive(Debug, Deserialize)]
-struct ManifestFile {
-    file: String,
-}
+    /// Update the firmware: upload the images, mark them, reset, wait for the device and check
+    /// that the new images run, then confirm them. Returns the final state of the images.
+    pub fn flash<F>(
+        &mut self,
+        filename: &Path,
+        options: &FlashOptions,
+        progress: Option<F>,
+    ) -> Result<Vec<ImageStateEntry>, Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        self.upload(filename, &options.upload, progress)?;
 
-#[derive(Debug, Deserialize)]
-struct Manifest {
-    //version_MCUBOOT: String,
-    files: Vec<ManifestFile>,
-}
+        // the hashes tell the new images apart on the device, forced uploads without an
+        // MCUboot header have none and are not checked
+        let mut images = Vec::new();
+        for image in select_images(filename, &options.upload)? {
+            let index = image.image_index.unwrap_or(options.upload.slot);
+            match image_hash(&image.data) {
+                Ok(hash) => images.push((index as u32, hash)),
+                Err(e) if options.upload.force => {
+                    warn!("image {} is uploaded, but not checked: {}", index, e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
 
-pub fn parse_data(filename: &PathBuf) -> Result<Vec<u8>, Error> {
-    if filename.extension() == Some(OsStr::new("zip")) {
-        let zipfile = std::fs::File::open(filename)?;
-        let mut archive = zip::ZipArchive::new(zipfile)?;
-        let file = archive.by_name("manifest.json")?;
-        let manifest: Manifest = serde_json::from_reader(file)?;
-        let filename = manifest.files.first().unwrap().file.clone();
-        let mut file = archive.by_name(&filename)?;
-        let mut buf: Vec<u8> = Vec::new();
-        file.read_to_end(&mut buf)?;
-        Ok(buf)
-    } else if filename.extension() == Some(OsStr::new("bin")) {
-        read(filename).map_err(anyhow::Error::from)
-    } else {
-        bail!("Only .bin and .zip files are supported");
+        // boot the new images, unless they run already
+        let state = self.list()?;
+        let mut reset = false;
+        for (image, hash) in &images {
+            let Some(entry) = state
+                .images
+                .iter()
+                .find(|e| e.image == *image && e.hash == *hash)
+            else {
+                bail!("uploaded image {} not found on the device", image);
+            };
+            if !entry.active {
+                info!(
+                    "marking image {} slot {} {}",
+                    entry.image,
+                    entry.slot,
+                    if options.mode == FlashMode::Permanent {
+                        "permanent"
+                    } else {
+                        "for test"
+                    }
+                );
+                self.test(hash.clone(), Some(options.mode == FlashMode::Permanent))?;
+                reset = true;
+            }
+        }
+        if reset {
+            self.reset()?;
+            self.reconnect(options.timeout)?;
+        }
+
+        for (image, hash) in &images {
+            let entry = match self.find_image(hash, *image)? {
+                Some(entry) if entry.active => entry,
+                _ => bail!("device did not boot the new image {}", image),
+            };
+            if options.mode == FlashMode::Confirm && !entry.confirmed {
+                info!("confirming image {} slot {}", entry.image, entry.slot);
+                self.test(hash.clone(), Some(true))?;
+            }
+        }
+
+        // final state check
+        let mut entries = Vec::new();
+        for (image, hash) in &images {
+            let entry = match self.find_image(hash, *image)? {
+                Some(entry) if entry.active => entry,
+                _ => bail!("new image {} is not active anymore", image),
+            };
+            if options.mode != FlashMode::Test && !entry.confirmed {
+                bail!("new image {} is not confirmed", image);
+            }
+            info!(
+                "image {} slot {} runs version {}",
+                entry.image, entry.slot, entry.version
+            );
+            entries.push(entry);
+        }
+        Ok(entries)
     }
-}
 
-pub fn upload<F>(
-    specs: &SerialSpecs,
-    filename: &PathBuf,
-    slot: u8,
-    mut progress: Option<F>,
-) -> Result<(), Error>
-where
-    F: FnMut(u64, u64),
-{
-    info!("flashing file {}", filename.to_string_lossy());
-
-    let data = parse_data(&filename)?;
-
-    info!("flashing {} bytes to slot {}", data.len(), slot);
-
-    // open serial port
-    let mut port = open_port(specs)?;
-
-    // transfer in blocks
-    let mut off: usize = 0;
-    let start_time = Instant::now();
-    let mut sent_blocks: u32 = 0;
-    let mut confirmed_blocks: u32 = 0;
-    loop {
-        let mut nb_retry = specs.nb_retry;
-        let off_start = off;
-        let mut try_length = specs.mtu;
-        debug!("try_length: {}", try_length);
-        let seq_id = next_seq_id();
-        loop {
-            // get slot
-            let image_num = slot;
-
-            // create image upload request
-            if off + try_length > data.len() {
-                try_length = data.len() - off;
+    pub fn upload<F>(
+        &mut self,
+        filename: &Path,
+        options: &UploadOptions,
+        mut progress: Option<F>,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        info!("flashing file {}", filename.to_string_lossy());
+
+        for image in select_images(filename, options)? {
+            // images of a package go to their own image number
+            let slot = image.image_index.unwrap_or(options.slot);
+            if let Some(board) = &image.board {
+                info!("image {} for {}", image.file, board);
             }
-            let chunk = data[off..off + try_length].to_vec();
-            let len = data.len() as u32;
-            let req = if off == 0 {
-                ImageUploadReq {
-                    image_num,
-                    off: off as u32,
-                    len: Some(len),
-                    data_sha: Some(Sha256::digest(&data).to_vec()),
-                    upgrade: None,
-                    data: chunk,
-                }
+            debug!(
+                "version {:?}, load address {:?}",
+                image.version, image.load_address
+            );
+            self.upload_image(&image.data, slot, options, &mut progress)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn upload_image<F>(
+        &mut self,
+        data: &[u8],
+        slot: u8,
+        options: &UploadOptions,
+        progress: &mut Option<F>,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        if !options.force {
+            if let Err(e) = ImageHeader::parse(data) {
+                bail!("refusing to upload a file without MCUboot header: {}", e);
+            }
+        }
+
+        if options.skip_if_present {
+            let hash = image_hash(data)?;
+            if let Some(entry) = self.find_image(&hash, slot as u32)? {
+                info!(
+                    "image already present in image {} slot {}, skipping upload",
+                    entry.image, entry.slot
+                );
+                return Ok(());
+            }
+        }
+
+        info!("flashing {} bytes to slot {}", data.len(), slot);
+
+        let start_time = Instant::now();
+        let (sent_blocks, confirmed_blocks) = self.with_initial_timeout(|session| {
+            let off = if options.resume {
+                session.upload_first_chunk(data, slot, progress)?
             } else {
-                ImageUploadReq {
-                    image_num,
-                    off: off as u32,
-                    len: None,
-                    data_sha: None,
-                    upgrade: None,
-                    data: chunk,
-                }
+                0
             };
-            debug!("req: {:?}", req);
-
-            // convert to bytes with CBOR
-            let body = serde_cbor::to_vec(&req)?;
-            let (chunk, request_header) = encode_request(
-                specs.linelength,
-                NmpOp::Write,
-                NmpGroup::Image,
-                NmpIdImage::Upload,
-                &body,
-                seq_id,
-            )?;
-
-            // test if too long
-            if chunk.len() > specs.mtu {
-                let reduce = chunk.len() - specs.mtu;
-                if reduce > try_length {
-                    bail!("MTU too small");
+
+            // transfer in blocks
+            if off == data.len() {
+                Ok((0, 0))
+            } else if session.specs.window > 1 {
+                session.upload_windowed(data, slot, off, progress)
+            } else {
+                session.upload_blocks(data, slot, off, progress)
+            }
+        })?;
+
+        let elapsed = start_time.elapsed().as_secs_f64().round();
+        let elapsed_duration = Duration::from_secs(elapsed as u64);
+        let formatted_duration = format_duration(elapsed_duration);
+        info!("upload took {}", formatted_duration);
+        if confirmed_blocks != sent_blocks {
+            warn!(
+                "upload packet loss {}%",
+                100 - confirmed_blocks * 100 / sent_blocks
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Send the first chunk with the length and hash of the image, and return the offset the
+    /// device answers with. A device which still holds part of the same image ignores the
+    /// chunk and answers with the offset it got to, instead of erasing the slot again.
+    fn upload_first_chunk<F>(
+        &mut self,
+        data: &[u8],
+        image_num: u8,
+        progress: &mut Option<F>,
+    ) -> Result<usize, Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        let seq_id = self.next_seq();
+        let (frame, request_header, len) = self.upload_frame(data, 0, image_num, seq_id)?;
+        let response_body = self.transceive_retry(&frame, &request_header)?;
+        let Some(off) = upload_rsp_off(&response_body)? else {
+            bail!("wrong offset received");
+        };
+        if off == 0 || off > data.len() {
+            bail!("wrong offset received");
+        }
+        if off > len {
+            info!(
+                "resuming upload at offset {}, skipped {} bytes",
+                off,
+                off - len
+            );
+        }
+        if let Some(ref mut f) = progress {
+            f(off as u64, data.len() as u64);
+        }
+        Ok(off)
+    }
+
+    /// Build the upload request for the chunk at `off`, as long as it fits into the MTU.
+    ///
+    /// Returns the frame, its header and the number of image bytes it carries.
+    fn upload_frame(
+        &self,
+        data: &[u8],
+        off: usize,
+        image_num: u8,
+        seq_id: u8,
+    ) -> Result<(Vec<u8>, NmpHdr, usize), Error> {
+        let max_len = min(self.specs.mtu, data.len() - off);
+        self.chunk_frame(
+            NmpOp::Write,
+            NmpGroup::Image,
+            NmpIdImage::Upload,
+            seq_id,
+            max_len,
+            |try_length| {
+                // create image upload request
+                let chunk = data[off..off + try_length].to_vec();
+                let len = data.len() as u32;
+                let req = if off == 0 {
+                    ImageUploadReq {
+                        image_num,
+                        off: off as u32,
+                        len: Some(len),
+                        data_sha: Some(Sha256::digest(data).to_vec()),
+                        upgrade: None,
+                        data: chunk,
+                    }
+                } else {
+                    ImageUploadReq {
+                        image_num,
+                        off: off as u32,
+                        len: None,
+                        data_sha: None,
+                        upgrade: None,
+                        data: chunk,
+                    }
+                };
+                debug!("req: {:?}", req);
+
+                // convert to bytes with CBOR
+                Ok(serde_cbor::to_vec(&req)?)
+            },
+        )
+    }
+
+    /// Upload one chunk at a time, waiting for each response before sending the next chunk.
+    fn upload_blocks<F>(
+        &mut self,
+        data: &[u8],
+        image_num: u8,
+        mut off: usize,
+        progress: &mut Option<F>,
+    ) -> Result<(u32, u32), Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        let mut sent_blocks: u32 = 0;
+        let mut confirmed_blocks: u32 = 0;
+        'chunks: loop {
+            let mut nb_retry = self.specs.nb_retry;
+            let off_start = off;
+            let seq_id = self.next_seq();
+            let (frame, request_header, _) = self.upload_frame(data, off, image_num, seq_id)?;
+            loop {
+                // send request
+                sent_blocks += 1;
+                let (response_header, response_body) =
+                    match self.transceive(&frame, &request_header) {
+                        Ok(ret) => ret,
+                        Err(e) if is_timeout(&e) => {
+                            if nb_retry == 0 {
+                                return Err(e);
+                            }
+                            nb_retry -= 1;
+                            debug!("missed answer, nb_retry: {}", nb_retry);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+
+                check_answer(&request_header, &response_header)?;
+                if self.version_fallback(&request_header, &response_header, &response_body) {
+                    // send the chunk again, in SMP v1
+                    continue 'chunks;
                 }
 
-                // number of bytes to reduce is base64 encoded, calculate back the number of bytes
-                // and then reduce a bit more for base64 filling and rounding
-                try_length -= reduce * 3 / 4 + 3;
-                debug!("new try_length: {}", try_length);
-                continue;
+                // verify result code and update offset
+                if let Some(off_val) = upload_rsp_off(&response_body)? {
+                    off = off_val;
+                }
+                confirmed_blocks += 1;
+                break;
+            }
+
+            // next chunk, next off should have been sent from the device
+            if off_start == off {
+                bail!("wrong offset received");
+            }
+
+            if let Some(ref mut f) = progress {
+                f(off as u64, data.len() as u64);
+            }
+
+            //info!("{}% uploaded", 100 * off / data.len());
+            if off == data.len() {
+                break;
+            }
+
+            self.set_subsequent_timeout()?;
+        }
+
+        Ok((sent_blocks, confirmed_blocks))
+    }
+
+    /// Upload with up to `specs.window` chunks in flight, matching the responses by sequence number.
+    ///
+    /// When a response is missing, or the device reports another offset than expected, all
+    /// chunks in flight are dropped and the upload continues from the last acknowledged offset.
+    fn upload_windowed<F>(
+        &mut self,
+        data: &[u8],
+        image_num: u8,
+        off: usize,
+        progress: &mut Option<F>,
+    ) -> Result<(u32, u32), Error>
+    where
+        F: FnMut(u64, u64),
+    {
+        let mut acked = off;
+        let mut next_off = off;
+        let mut in_flight: VecDeque<(NmpHdr, usize)> = VecDeque::new();
+        let mut nb_retry = self.specs.nb_retry;
+        let mut sent_blocks: u32 = 0;
+        let mut confirmed_blocks: u32 = 0;
+        while acked < data.len() {
+            // the first chunk makes the device erase the slot, so send it alone
+            let window = if acked == 0 { 1 } else { self.specs.window };
+            while in_flight.len() < window && next_off < data.len() {
+                let seq_id = self.next_seq();
+                let (frame, request_header, len) =
+                    self.upload_frame(data, next_off, image_num, seq_id)?;
+                self.transport.send(&frame)?;
+                sent_blocks += 1;
+                next_off += len;
+                in_flight.push_back((request_header, next_off));
             }
 
-            // send request
-            sent_blocks += 1;
-            let (response_header, response_body) = match transceive(&mut *port, &chunk) {
-                Ok(ret) => ret,
-                Err(e) if e.to_string() == "Operation timed out" => {
+            let (response_header, response_body) = match self.transport.recv() {
+                Ok(ret) => decode_frame(&ret)?,
+                Err(e) if is_timeout(&e) => {
                     if nb_retry == 0 {
                         return Err(e);
                     }
                     nb_retry -= 1;
-                    debug!("missed answer, nb_retry: {}", nb_retry);
+                    debug!("missed answer at offset {}, nb_retry: {}", acked, nb_retry);
+                    in_flight.clear();
+                    next_off = acked;
                     continue;
                 }
                 Err(e) => return Err(e),
             };
 
-            if !check_answer(&request_header, &response_header) {
-                bail!("wrong answer types")
+            // answers to requests which were already given up are ignored
+            let Some(pos) = in_flight
+                .iter()
+                .position(|(request_header, _)| request_header.seq == response_header.seq)
+            else {
+                debug!(
+                    "ignoring answer with sequence number {}",
+                    response_header.seq
+                );
+                continue;
+            };
+            let (request_header, expected_off) = in_flight[pos];
+            check_answer(&request_header, &response_header)?;
+            if self.version_fallback(&request_header, &response_header, &response_body) {
+                in_flight.clear();
+                next_off = acked;
+                continue;
             }
+            confirmed_blocks += 1;
 
-            // verify result code and update offset
-            debug!(
-                "response_body: {}",
-                serde_json::to_string_pretty(&response_body)?
-            );
-            if let serde_cbor::Value::Map(object) = response_body {
-                for (key, val) in object.iter() {
-                    match key {
-                        serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                            if let serde_cbor::Value::Integer(rc) = val {
-                                if *rc != 0 {
-                                    bail!("rc = {}", rc);
-                                }
-                            }
-                        }
-                        serde_cbor::Value::Text(off_key) if off_key == "off" => {
-                            if let serde_cbor::Value::Integer(off_val) = val {
-                                off = *off_val as usize;
-                            }
-                        }
-                        _ => (),
-                    }
+            let Some(off) = upload_rsp_off(&response_body)? else {
+                bail!("wrong offset received");
+            };
+            if pos == 0 && off == expected_off {
+                in_flight.pop_front();
+            } else {
+                // out of order, or the device missed a chunk: continue where the device is
+                debug!("expected offset {}, device is at {}", expected_off, off);
+                in_flight.clear();
+                next_off = off;
+            }
+
+            if off > acked {
+                acked = off;
+                nb_retry = self.specs.nb_retry;
+            } else {
+                if nb_retry == 0 {
+                    bail!("wrong offset received");
                 }
+                nb_retry -= 1;
+                continue;
             }
-            confirmed_blocks += 1;
-            break;
-        }
 
-        // next chunk, next off should have been sent from the device
-        if off_start == off {
-            bail!("wrong offset received");
-        }
+            if let Some(ref mut f) = progress {
+                f(acked as u64, data.len() as u64);
+            }
 
-        if let Some(ref mut f) = progress {
-            f(off as u64, data.len() as u64);
+            self.set_subsequent_timeout()?;
         }
 
-        //info!("{}% uploaded", 100 * off / data.len());
-        if off == data.len() {
-            break;
-        }
+        Ok((sent_blocks, confirmed_blocks))
+    }
+}
 
-        // The first packet was sent and the device has cleared its internal flash
-        // We can now lower the timeout in case of failed transmission
-        port.set_timeout(Duration::from_millis(specs.subsequent_timeout_ms as u64))?;
+/// Read the images of a firmware file which the upload options select.
+fn select_images(filename: &Path, options: &UploadOptions) -> Result<Vec<FirmwareImage>, Error> {
+    let mut images = parse_images(filename)?;
+    if !options.images.is_empty() {
+        images.retain(|image| {
+            image
+                .image_index
+                .is_some_and(|index| options.images.contains(&index))
+        });
+        if images.is_empty() {
+            bail!("none of the selected images found in the file");
+        }
     }
+    Ok(images)
+}
 
-    let elapsed = start_time.elapsed().as_secs_f64().round();
-    let elapsed_duration = Duration::from_secs(elapsed as u64);
-    let formatted_duration = format_duration(elapsed_duration);
-    info!("upload took {}", formatted_duration);
-    if confirmed_blocks != sent_blocks {
-        warn!(
-            "upload packet loss {}%",
-            100 - confirmed_blocks * 100 / sent_blocks
-        );
+/// Check the result code of an upload response and return the offset the device reports.
+fn upload_rsp_off(response_body: &serde_cbor::Value) -> Result<Option<usize>, Error> {
+    debug!(
+        "response_body: {}",
+        serde_json::to_string_pretty(response_body)?
+    );
+    check_rc(response_body)?;
+    let mut off = None;
+    if let serde_cbor::Value::Map(object) = response_body {
+        for (key, val) in object.iter() {
+            match key {
+                serde_cbor::Value::Text(off_key) if off_key == "off" => {
+                    if let serde_cbor::Value::Integer(off_val) = val {
+                        off = Some(*off_val as usize);
+                    }
+                }
+                _ => (),
+            }
+        }
     }
+    Ok(off)
+}
+
+pub fn erase(specs: &SerialSpecs, slot: Option<u32>) -> Result<(), Error> {
+    Session::open(specs)?.erase(slot)
+}
 
-    Ok(())
+pub fn test(specs: &SerialSpecs, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error> {
+    Session::open(specs)?.test(hash, confirm)
+}
+
+pub fn list(specs: &SerialSpecs) -> Result<ImageStateRsp, Error> {
+    Session::open(specs)?.list()
+}
+
+pub fn upload<F>(
+    specs: &SerialSpecs,
+    filename: &Path,
+    slot: u8,
+    progress: Option<F>,
+) -> Result<(), Error>
+where
+    F: FnMut(u64, u64),
+{
+    let options = UploadOptions {
+        slot,
+        ..Default::default()
+    };
+    Session::open(specs)?.upload(filename, &options, progress)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::TransportError;
+    use crate::mcuboot::tests::test_image;
+    use crate::sim::{core_dump, SimDevice, SimOptions};
+    use crate::test_util::{sim_session, temp_file, test_specs};
+    use crate::transfer::{encode_versioned_frame, SmpTransport};
+    use std::cell::{Cell, RefCell};
+    use std::net::UdpSocket;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::thread;
 
     #[test]
     fn parse_manifest() {
         let data = parse_data(&PathBuf::from("dfu_application.zip"));
         assert_eq!(data.unwrap().len(), 225251);
     }
+
+    #[test]
+    fn multi_image_package() {
+        use zip::write::SimpleFileOptions;
+
+        let filename = std::env::temp_dir().join("mcumgr-client-multi-image.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&filename).unwrap());
+        let manifest = r#"{"format-version": 0, "files": [
+            {"file": "app.bin", "image_index": "0", "slot": "1", "load_address": 65536,
+             "version_MCUBOOT": "1.2.3+4", "board": "nrf5340dk_nrf5340_cpuapp"},
+            {"file": "net.bin", "image_index": 1, "board": "nrf5340dk_nrf5340_cpunet"}]}"#;
+        for (name, content) in [
+            ("manifest.json", manifest.as_bytes()),
+            ("app.bin", &[1, 2, 3]),
+            ("net.bin", &[4, 5]),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            std::io::Write::write_all(&mut zip, content).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let images = parse_images(&filename).unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].image_index, Some(0));
+        assert_eq!(images[0].slot, Some(1));
+        assert_eq!(images[0].version.as_deref(), Some("1.2.3+4"));
+        assert_eq!(images[0].data, vec![1, 2, 3]);
+        assert_eq!(images[1].image_index, Some(1));
+        assert_eq!(images[1].data, vec![4, 5]);
+        assert!(parse_data(&filename).is_err());
+    }
+
+    #[test]
+    fn windowed_upload() {
+        let image = test_image(&[0x5a; 10000], &[0x55; 32]);
+        let filename = temp_file("windowed-upload.bin", &image);
+        let specs = SerialSpecs {
+            window: 4,
+            ..test_specs()
+        };
+        let mut last = 0;
+        upload(
+            &specs,
+            &filename,
+            0,
+            Some(|off, total| {
+                assert!(off > last && off <= total);
+                last = off;
+            }),
+        )
+        .unwrap();
+        assert_eq!(last, image.len() as u64);
+    }
+
+    /// Passes requests to a simulated device as long as the shared budget lasts, and
+    /// records the data length of each upload request.
+    struct Interrupted {
+        device: SimDevice,
+        budget: Rc<Cell<usize>>,
+        chunks: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl SmpTransport for Interrupted {
+        fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+            if self.budget.get() == 0 {
+                return Ok(());
+            }
+            self.budget.set(self.budget.get() - 1);
+            let (header, body) = decode_frame(frame)?;
+            if header.id == NmpIdImage::Upload as u8 {
+                let req: ImageUploadReq = serde_cbor::value::from_value(body)?;
+                self.chunks.borrow_mut().push(req.data.len());
+            }
+            self.device.send(frame)
+        }
+
+        fn recv(&mut self) -> Result<Vec<u8>, Error> {
+            self.device.recv()
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resume_interrupted_upload() {
+        let image = test_image(&[0x5a; 5000], &[0x33; 32]);
+        let filename = temp_file("resume.bin", &image);
+        let budget = Rc::new(Cell::new(5));
+        let chunks = Rc::new(RefCell::new(Vec::new()));
+        let transport = Interrupted {
+            device: SimDevice::new(SimOptions::default()),
+            budget: budget.clone(),
+            chunks: chunks.clone(),
+        };
+        let mut session = Session::with_transport(&test_specs(), Box::new(transport));
+
+        // the device stops answering after 5 chunks
+        let mut acked = 0;
+        let options = UploadOptions::default();
+        assert!(session
+            .upload(&filename, &options, Some(|off, _| acked = off))
+            .is_err());
+        let acked = acked as usize;
+        assert_eq!(acked, chunks.borrow().iter().sum::<usize>());
+
+        // the device ignores the first chunk and continues where it stopped
+        budget.set(usize::MAX);
+        chunks.borrow_mut().clear();
+        let options = UploadOptions {
+            resume: true,
+            ..Default::default()
+        };
+        let mut offsets = Vec::new();
+        session
+            .upload(&filename, &options, Some(|off, _| offsets.push(off)))
+            .unwrap();
+        assert_eq!(offsets[0], acked as u64);
+        assert_eq!(*offsets.last().unwrap(), image.len() as u64);
+        let chunks = chunks.borrow();
+        let skipped = image.len() + chunks[0] - chunks.iter().sum::<usize>();
+        assert_eq!(skipped, acked);
+
+        let state = session.list().unwrap();
+        assert_eq!(state.images[0].hash, vec![0x33; 32]);
+    }
+
+    /// Write a package with the images for image numbers 0, 1, ...
+    fn test_package(name: &str, images: &[Vec<u8>]) -> PathBuf {
+        use zip::write::SimpleFileOptions;
+
+        let filename = std::env::temp_dir().join(format!("mcumgr-client-{}", name));
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&filename).unwrap());
+        let files: Vec<String> = (0..images.len())
+            .map(|i| format!(r#"{{"file": "image{}.bin", "image_index": "{}"}}"#, i, i))
+            .collect();
+        let manifest = format!(r#"{{"format-version": 0, "files": [{}]}}"#, files.join(","));
+        zip.start_file("manifest.json", SimpleFileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut zip, manifest.as_bytes()).unwrap();
+        for (i, image) in images.iter().enumerate() {
+            zip.start_file(format!("image{}.bin", i), SimpleFileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut zip, image).unwrap();
+        }
+        zip.finish().unwrap();
+        filename
+    }
+
+    /// Passes requests to a simulated device, and records the timeout at the first chunk
+    /// of each image upload.
+    struct Timed {
+        device: SimDevice,
+        timeout: Rc<Cell<Duration>>,
+        first_chunks: Rc<RefCell<Vec<(u8, Duration)>>>,
+    }
+
+    impl SmpTransport for Timed {
+        fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+            let (header, body) = decode_frame(frame)?;
+            if header.id == NmpIdImage::Upload as u8 {
+                let req: ImageUploadReq = serde_cbor::value::from_value(body)?;
+                if req.off == 0 {
+                    self.first_chunks
+                        .borrow_mut()
+                        .push((req.image_num, self.timeout.get()));
+                }
+            }
+            self.device.send(frame)
+        }
+
+        fn recv(&mut self) -> Result<Vec<u8>, Error> {
+            self.device.recv()
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+            self.timeout.set(timeout);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn initial_timeout_for_each_image() {
+        let filename = test_package(
+            "timeout-package.zip",
+            &[
+                test_image(&[0x5a; 3000], &[0x11; 32]),
+                test_image(&[0xa5; 2000], &[0x22; 32]),
+            ],
+        );
+        let specs = test_specs();
+        let initial = Duration::from_secs(specs.initial_timeout_s as u64);
+        let timeout = Rc::new(Cell::new(initial));
+        let first_chunks = Rc::new(RefCell::new(Vec::new()));
+        let transport = Timed {
+            device: SimDevice::new(SimOptions {
+                images: 2,
+                ..Default::default()
+            }),
+            timeout: timeout.clone(),
+            first_chunks: first_chunks.clone(),
+        };
+        let mut session = Session::with_transport(&specs, Box::new(transport));
+
+        for window in [1, 4] {
+            session.specs.window = window;
+            first_chunks.borrow_mut().clear();
+            session
+                .upload(&filename, &UploadOptions::default(), None::<fn(u64, u64)>)
+                .unwrap();
+            assert_eq!(*first_chunks.borrow(), vec![(0, initial), (1, initial)]);
+            assert_eq!(timeout.get(), initial);
+        }
+    }
+
+    #[test]
+    fn core_dump_download_and_erase() {
+        let mut session = sim_session(test_specs(), SimOptions::default());
+        assert!(!session.core_list().unwrap());
+
+        session.crash("assert").unwrap();
+        assert!(session.core_list().unwrap());
+        let filename = temp_file("core.bin", &[]);
+        let mut chunks = 0;
+        session
+            .core_download(
+                &filename,
+                Some(|off, total| {
+                    assert!(off <= total);
+                    chunks += 1;
+                }),
+            )
+            .unwrap();
+        assert!(chunks > 1);
+        assert_eq!(read(&filename).unwrap(), core_dump());
+
+        session.core_erase().unwrap();
+        assert!(!session.core_list().unwrap());
+        assert!(session
+            .core_download(&filename, None::<fn(u64, u64)>)
+            .is_err());
+    }
+
+    /// Answers each request with the next core load answer.
+    struct CoreAnswers {
+        answers: VecDeque<CoreLoadRsp>,
+        responses: VecDeque<Vec<u8>>,
+    }
+
+    impl SmpTransport for CoreAnswers {
+        fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+            let (header, _) = decode_frame(frame)?;
+            if let Some(rsp) = self.answers.pop_front() {
+                let body = serde_cbor::to_vec(&rsp)?;
+                let (frame, _) = encode_versioned_frame(
+                    header.version,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Image,
+                    NmpIdImage::CoreLoad,
+                    &body,
+                    header.seq,
+                )?;
+                self.responses.push_back(frame);
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<Vec<u8>, Error> {
+            self.responses
+                .pop_front()
+                .ok_or_else(|| SmpError::from(TransportError::Timeout).into())
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn core_dump_broken_answers() {
+        let download = |answers: Vec<CoreLoadRsp>| {
+            let transport = CoreAnswers {
+                answers: answers.into(),
+                responses: VecDeque::new(),
+            };
+            let mut session = Session::with_transport(&test_specs(), Box::new(transport));
+            let filename = temp_file("core-broken.bin", &[]);
+            session
+                .core_download(&filename, None::<fn(u64, u64)>)
+                .unwrap_err()
+                .to_string()
+        };
+        let chunk = |off: u32, len: Option<u32>| CoreLoadRsp {
+            off,
+            data: vec![0x55; 100],
+            len,
+        };
+
+        assert_eq!(
+            download(vec![chunk(0, Some(300)), chunk(50, None)]),
+            "wrong offset received"
+        );
+        assert_eq!(
+            download(vec![chunk(0, None)]),
+            "core dump length missing in answer"
+        );
+    }
+
+    #[test]
+    fn skip_upload_if_present() {
+        // the test transport holds this image active in image 1
+        let hash = hex::decode("61ddbce8f52e53715f57b360a5af0700ba17122114c94a11b86d9097f7e09cc3")
+            .unwrap();
+        let filename = temp_file("skip-if-present.bin", &test_image(&[0x5a; 1000], &hash));
+        let mut session = Session::open(&test_specs()).unwrap();
+        for (slot, uploaded) in [(1, false), (0, true)] {
+            let options = UploadOptions {
+                slot,
+                skip_if_present: true,
+                ..Default::default()
+            };
+            let mut called = false;
+            session
+                .upload(&filename, &options, Some(|_, _| called = true))
+                .unwrap();
+            assert_eq!(called, uploaded);
+        }
+    }
+
+    #[test]
+    fn flash_image_already_running() {
+        let hash = hex::decode("61ddbce8f52e53715f57b360a5af0700ba17122114c94a11b86d9097f7e09cc3")
+            .unwrap();
+        let filename = temp_file("flash-running.bin", &test_image(&[0x5a; 1000], &hash));
+        let options = FlashOptions {
+            upload: UploadOptions {
+                slot: 1,
+                skip_if_present: true,
+                ..Default::default()
+            },
+            mode: FlashMode::Test,
+            timeout: Duration::from_secs(1),
+        };
+        let entries = Session::open(&test_specs())
+            .unwrap()
+            .flash(&filename, &options, None::<fn(u64, u64)>)
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].active);
+    }
+
+    #[test]
+    fn flash_multi_image_package() {
+        let hashes = [vec![0x11; 32], vec![0x22; 32]];
+        let filename = test_package(
+            "flash-package.zip",
+            &[
+                test_image(&[0x5a; 3000], &hashes[0]),
+                test_image(&[0xa5; 2000], &hashes[1]),
+            ],
+        );
+
+        // the device resets in between, so it is served over UDP like a real one
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let specs = SerialSpecs {
+            device: format!("udp://{}", socket.local_addr().unwrap()),
+            ..test_specs()
+        };
+        thread::spawn(move || {
+            SimDevice::new(SimOptions {
+                images: 2,
+                ..Default::default()
+            })
+            .serve_udp(&socket)
+        });
+
+        let options = FlashOptions {
+            upload: UploadOptions::default(),
+            mode: FlashMode::Confirm,
+            timeout: Duration::from_secs(10),
+        };
+        let mut session = Session::open(&specs).unwrap();
+        let entries = session
+            .flash(&filename, &options, None::<fn(u64, u64)>)
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        for (image, (entry, hash)) in entries.iter().zip(&hashes).enumerate() {
+            assert_eq!((entry.image, &entry.hash), (image as u32, hash));
+            assert!(entry.active && entry.confirmed);
+        }
+
+        // only the selected image is flashed again
+        let options = FlashOptions {
+            upload: UploadOptions {
+                images: vec![1],
+                ..Default::default()
+            },
+            ..options
+        };
+        let entries = session
+            .flash(&filename, &options, None::<fn(u64, u64)>)
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].image, &entries[0].hash), (1, &hashes[1]));
+    }
+
+    #[test]
+    fn flash_forced_non_mcuboot_image() {
+        let filename = temp_file("flash-no-magic.bin", &[0x5a; 100]);
+        let options = FlashOptions {
+            upload: UploadOptions {
+                slot: 1,
+                force: true,
+                ..Default::default()
+            },
+            mode: FlashMode::Confirm,
+            timeout: Duration::from_secs(1),
+        };
+        let entries = Session::open(&test_specs())
+            .unwrap()
+            .flash(&filename, &options, None::<fn(u64, u64)>)
+            .unwrap();
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn upload_refuses_non_mcuboot_image() {
+        let filename = temp_file("no-magic.bin", &[0x5a; 100]);
+        assert!(upload(&test_specs(), &filename, 0, None::<fn(u64, u64)>).is_err());
+    }
 }
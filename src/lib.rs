@@ -1,12 +1,50 @@
+mod crash;
 mod default;
+mod error;
+mod firmware;
+mod fs;
 mod image;
+mod logs;
+mod mcuboot;
 mod nmp_hdr;
-mod test_serial_port;
+mod run;
+mod session;
+mod settings;
+mod shell;
+#[cfg(any(test, feature = "sim"))]
+mod sim;
+mod stat;
+mod test_transport;
+#[cfg(test)]
+mod test_util;
 mod transfer;
+mod udp;
 
+pub use crate::crash::CRASH_TYPES;
 pub use crate::default::reset;
-pub use crate::image::{erase, list, test, upload};
-pub use crate::transfer::SerialSpecs;
+pub use crate::error::{DeviceError, SmpError, TransportError};
+pub use crate::firmware::{parse_elf, parse_ihex, parse_srec, FileFormat};
+pub use crate::image::{
+    erase, list, parse_data, parse_images, test, upload, FirmwareImage, FlashMode, FlashOptions,
+    UploadOptions,
+};
+pub use crate::mcuboot::{
+    image_hash, ImageDependency, ImageHeader, ImageTlv, ImageVersion, McubootImage,
+};
+pub use crate::nmp_hdr::{
+    FsHashRsp, FsStatusRsp, ImageStateEntry, ImageStateRsp, LogEntry, LogShowLog, LogShowRsp,
+    McumgrParamsRsp, MpStatEntry, MpStatRsp, NmpErr, NmpFsErr, NmpGroup, NmpHdr, NmpImageErr,
+    NmpOp, ShellExecRsp, StatReadRsp, TaskStatEntry, TaskStatRsp,
+};
+pub use crate::run::RunResults;
+pub use crate::session::Session;
+#[cfg(feature = "sim")]
+pub use crate::sim::{SimDevice, SimOptions};
+pub use crate::transfer::{
+    decode_frame, encode_frame, encode_serial_frame, log_console, read_serial_frame,
+    read_serial_frame_with, SerialSpecs, SerialTransport, SmpTransport,
+};
+pub use crate::udp::UdpTransport;
 
 // use reqwest::header::USER_AGENT;
 // let client = reqwest::Client::new();
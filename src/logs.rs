@@ -0,0 +1,130 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::nmp_hdr::*;
+use crate::session::check_rc;
+use crate::session::empty_body;
+use crate::session::Session;
+
+impl Session {
+    /// Read the entries of one log, or of all logs, starting at `index`.
+    /// The `next_index` of the answer continues where this read stopped.
+    pub fn log_show(
+        &mut self,
+        log_name: Option<&str>,
+        index: Option<u32>,
+    ) -> Result<LogShowRsp, Error> {
+        debug!("send log show request");
+
+        let req = LogShowReq {
+            log_name: log_name.map(str::to_string),
+            index,
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        self.request_rsp(NmpOp::Read, NmpGroup::Log, NmpIdLog::Show, &body)
+    }
+
+    /// Clear all logs.
+    pub fn log_clear(&mut self) -> Result<(), Error> {
+        info!("send log clear request");
+
+        let body = empty_body()?;
+        let (_, response_body) =
+            self.request(NmpOp::Write, NmpGroup::Log, NmpIdLog::Clear, &body)?;
+        check_rc(&response_body)
+    }
+
+    /// List the names of the logs.
+    pub fn log_list(&mut self) -> Result<Vec<String>, Error> {
+        info!("send log list request");
+
+        let body = empty_body()?;
+        let rsp: LogListRsp =
+            self.request_rsp(NmpOp::Read, NmpGroup::Log, NmpIdLog::List, &body)?;
+
+        Ok(rsp.log_list)
+    }
+
+    /// List the log modules with their ids.
+    pub fn log_modules(&mut self) -> Result<BTreeMap<String, u32>, Error> {
+        info!("send log module list request");
+
+        let body = empty_body()?;
+        let rsp: LogModuleListRsp =
+            self.request_rsp(NmpOp::Read, NmpGroup::Log, NmpIdLog::ModuleList, &body)?;
+
+        Ok(rsp.module_map)
+    }
+
+    /// List the log levels with their ids.
+    pub fn log_levels(&mut self) -> Result<BTreeMap<String, u32>, Error> {
+        info!("send log level list request");
+
+        let body = empty_body()?;
+        let rsp: LogLevelListRsp =
+            self.request_rsp(NmpOp::Read, NmpGroup::Log, NmpIdLog::LevelList, &body)?;
+
+        Ok(rsp.level_map)
+    }
+}
+
+impl LogEntry {
+    /// The message as text, with binary messages shown as hex.
+    pub fn message(&self) -> String {
+        match &self.msg {
+            serde_cbor::Value::Text(text) => text.trim_end().to_string(),
+            serde_cbor::Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) if self.entry_type.as_deref() != Some("cbor") => {
+                    text.trim_end().to_string()
+                }
+                _ => hex::encode(bytes),
+            },
+            other => format!("{:?}", other),
+        }
+    }
+
+    /// Name of the level, like in the level list of the device.
+    pub fn level_name(&self) -> String {
+        match self.level {
+            0 => "DEBUG".to_string(),
+            1 => "INFO".to_string(),
+            2 => "WARN".to_string(),
+            3 => "ERROR".to_string(),
+            4 => "CRITICAL".to_string(),
+            level => format!("LEVEL{}", level),
+        }
+    }
+
+    /// The timestamp in RFC 3339 format.
+    pub fn timestamp(&self) -> String {
+        if self.ts < 0 {
+            return self.ts.to_string();
+        }
+        let time = UNIX_EPOCH + Duration::from_micros(self.ts as u64);
+        humantime::format_rfc3339_micros(time).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_entry_format() {
+        let entry = LogEntry {
+            msg: serde_cbor::Value::Bytes(b"booted\n".to_vec()),
+            ts: 1_500_000,
+            level: 2,
+            index: 7,
+            module: 0,
+            entry_type: None,
+        };
+        assert_eq!(entry.message(), "booted");
+        assert_eq!(entry.level_name(), "WARN");
+        assert_eq!(entry.timestamp(), "1970-01-01T00:00:01.500000Z");
+    }
+}
@@ -1,21 +1,25 @@
 // Copyright © 2023-2024 Vouch.io LLC
 
 use anyhow::{Error, Result};
-use clap::{Parser, Subcommand};
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
-use log::{error, info, LevelFilter};
+use log::{debug, error, info, LevelFilter};
 use serialport::{available_ports, SerialPortType};
 use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
 use std::env;
-use std::path::PathBuf;
+use std::io::{stdin, stdout, BufRead, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use mcumgr_client::*;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Device name
+    /// Device name, or udp://<host>[:<port>] for SMP over UDP
     #[arg(short, long, default_value = "")]
     device: String,
 
@@ -47,6 +51,10 @@ struct Cli {
     #[arg(short, long, default_value_t = 115_200)]
     baudrate: u32,
 
+    /// Number of upload requests in flight, 1 waits for each answer before sending the next
+    #[arg(short, long, default_value_t = 1)]
+    window: usize,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -61,6 +69,7 @@ impl From<&Cli> for SerialSpecs {
             linelength: cli.linelength,
             mtu: cli.mtu,
             baudrate: cli.baudrate,
+            window: cli.window,
         }
     }
 }
@@ -80,11 +89,72 @@ pub enum Commands {
         /// Slot number
         #[arg(short, long, default_value_t = 0)]
         slot: u8,
+
+        /// Continue an interrupted upload at the offset the device already holds
+        #[arg(long)]
+        resume: bool,
+
+        /// Upload the file even if it is not an MCUboot image
+        #[arg(long)]
+        force: bool,
+
+        /// Don't upload if the image is already active or pending on the device
+        #[arg(long)]
+        skip_if_present: bool,
+
+        /// Upload only this image number of a multi-image package, can be repeated
+        #[arg(short, long)]
+        image: Vec<u8>,
+    },
+
+    /// Update the firmware: upload, test, reset, wait for the device, check and confirm
+    Flash {
+        filename: PathBuf,
+
+        /// Slot number
+        #[arg(short, long, default_value_t = 0)]
+        slot: u8,
+
+        /// Upload the file even if it is not an MCUboot image
+        #[arg(long)]
+        force: bool,
+
+        /// Don't upload if the image is already active or pending on the device
+        #[arg(long)]
+        skip_if_present: bool,
+
+        /// Flash only this image number of a multi-image package, can be repeated
+        #[arg(short, long)]
+        image: Vec<u8>,
+
+        /// Don't confirm the new image, it is reverted on the next reset
+        #[arg(long, conflicts_with = "permanent")]
+        test_only: bool,
+
+        /// Mark the new image permanent before the reset
+        #[arg(long)]
+        permanent: bool,
+
+        /// Wait up to this long for the device after the reset
+        #[arg(short, long, value_parser = humantime::parse_duration, default_value = "60s")]
+        wait: Duration,
+    },
+
+    /// Inspect image files, without a device
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
     },
 
     /// Test image againt given hash
     Test {
-        hash: String,
+        #[arg(required_unless_present = "file")]
+        hash: Option<String>,
+
+        /// Take the hash from this image file instead
+        #[arg(short, long, conflicts_with = "hash")]
+        file: Option<PathBuf>,
+
         #[arg(short, long)]
         confirm: Option<bool>,
     },
@@ -94,6 +164,242 @@ pub enum Commands {
         #[arg(short, long)]
         slot: Option<u32>,
     },
+
+    /// Send a string to the device and print the echoed answer
+    Echo { text: String },
+
+    /// Enable or disable the console echo of the device
+    ConsoleEcho {
+        #[arg(action = ArgAction::Set, value_parser = BoolishValueParser::new())]
+        enable: bool,
+    },
+
+    /// Show the task (thread) statistics of the device
+    Taskstat,
+
+    /// Show the memory pool statistics of the device
+    Mpstat,
+
+    /// Read the date and time of the device, or set it when a value is given
+    Datetime {
+        /// Date and time to set, like 2024-01-31T12:00:00
+        value: Option<String>,
+    },
+
+    /// Show the MCUmgr buffer parameters of the device
+    Params,
+
+    /// Show OS and application info
+    OsInfo {
+        /// Format string, like the uname options, e.g. "sv" or "a"
+        format: Option<String>,
+    },
+
+    /// Access the file system of the device
+    Fs {
+        #[command(subcommand)]
+        command: FsCommands,
+    },
+
+    /// Run shell commands on the device, interactively without subcommand
+    Shell {
+        #[command(subcommand)]
+        command: Option<ShellCommands>,
+    },
+
+    /// Read statistics of the device
+    Stat {
+        #[command(subcommand)]
+        command: StatCommands,
+    },
+
+    /// Read and write the settings of the device
+    Settings {
+        #[command(subcommand)]
+        command: SettingsCommands,
+    },
+
+    /// Read the persistent logs of the device
+    Log {
+        #[command(subcommand)]
+        command: LogCommands,
+    },
+
+    /// Check, download and erase the core dump of the device
+    Core {
+        #[command(subcommand)]
+        command: CoreCommands,
+    },
+
+    /// List and run the test suites of the device
+    Run {
+        #[command(subcommand)]
+        command: RunCommands,
+    },
+
+    /// Make the device crash, to test its fault handling
+    Crash {
+        #[arg(value_parser = CRASH_TYPES)]
+        crash_type: String,
+
+        /// Wait up to this long, like 30s, for the device to answer again
+        #[arg(short, long, value_parser = humantime::parse_duration)]
+        wait: Option<Duration>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ImageCommands {
+    /// Show the MCUboot header and TLVs of an image file
+    Info { filename: PathBuf },
+}
+
+#[derive(Subcommand)]
+pub enum FsCommands {
+    /// Upload a local file to the device
+    Upload { local: PathBuf, remote: String },
+
+    /// Download a file from the device
+    Download { remote: String, local: PathBuf },
+
+    /// Show the length of a file on the device
+    Stat { remote: String },
+
+    /// Calculate a hash or checksum of a file on the device
+    Hash {
+        remote: String,
+
+        /// Hash type, like crc32 or sha256
+        #[arg(short = 't', long = "type")]
+        hash_type: Option<String>,
+    },
+
+    /// Close the files held open by the device
+    Close,
+}
+
+#[derive(Subcommand)]
+pub enum StatCommands {
+    /// List the statistics groups
+    List,
+
+    /// Read the counters of a statistics group
+    Read {
+        name: String,
+
+        /// Read again after each interval, like 1s or 500ms, and show the changes
+        #[arg(short, long, value_parser = humantime::parse_duration)]
+        watch: Option<Duration>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SettingsCommands {
+    /// Read a setting, shown as hex and as text if printable
+    Read { name: String },
+
+    /// Write a setting
+    Write {
+        name: String,
+
+        /// Value, as text unless --hex is given
+        #[arg(required_unless_present = "file")]
+        value: Option<String>,
+
+        /// The value is given as hex bytes
+        #[arg(long, conflicts_with = "file")]
+        hex: bool,
+
+        /// Read the value from a file
+        #[arg(short, long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+    },
+
+    /// Delete a setting
+    Delete { name: String },
+
+    /// Apply the written settings
+    Commit,
+
+    /// Load the settings from persistent storage
+    Load,
+
+    /// Save the settings to persistent storage
+    Save,
+}
+
+#[derive(Subcommand)]
+pub enum LogCommands {
+    /// Show log entries
+    Show {
+        /// Name of the log, all logs if not given
+        name: Option<String>,
+
+        /// Show the entries starting at this index
+        #[arg(long, conflicts_with = "follow")]
+        since: Option<u32>,
+
+        /// Keep showing new entries as they are logged
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Print each entry as a line of JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Clear all logs
+    Clear,
+
+    /// List the logs
+    List,
+
+    /// List the log modules
+    Modules,
+
+    /// List the log levels
+    Levels,
+}
+
+#[derive(Subcommand)]
+pub enum CoreCommands {
+    /// Check whether a core dump is present
+    List,
+
+    /// Download the core dump to a file
+    Download { filename: PathBuf },
+
+    /// Erase the core dump
+    Erase,
+}
+
+#[derive(Subcommand)]
+pub enum RunCommands {
+    /// List the test suites
+    List,
+
+    /// Run a test suite, or all suites
+    Test {
+        suite: Option<String>,
+
+        /// Token the device adds to the log messages of this run
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Collect the results from the log until the run is done, at most this long, and
+        /// fail if a test failed
+        #[arg(short, long, value_parser = humantime::parse_duration, default_value = "30s")]
+        wait: Duration,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ShellCommands {
+    /// Run a command and exit with its return code, e.g. `shell exec -- kernel threads`
+    Exec {
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        argv: Vec<String>,
+    },
 }
 
 /*
@@ -196,6 +502,453 @@ fn mcumgr_command(command: &Commands) -> Result<(), anyhow::Error> {
 }
 */
 
+fn run_command(session: &mut Session, command: &Commands) -> Result<(), Error> {
+    match command {
+        Commands::List => {
+            let v = session.list()?;
+            print!("response: {}", serde_json::to_string_pretty(&v)?);
+            Ok(())
+        }
+        Commands::Reset => session.reset(),
+        Commands::Upload {
+            filename,
+            slot,
+            resume,
+            force,
+            skip_if_present,
+            image,
+        } => {
+            let options = UploadOptions {
+                slot: *slot,
+                resume: *resume,
+                force: *force,
+                skip_if_present: *skip_if_present,
+                images: image.clone(),
+            };
+            session.upload(filename, &options, Some(progress_bar("upload complete")))
+        }
+        Commands::Flash {
+            filename,
+            slot,
+            force,
+            skip_if_present,
+            image,
+            test_only,
+            permanent,
+            wait,
+        } => {
+            let options = FlashOptions {
+                upload: UploadOptions {
+                    slot: *slot,
+                    force: *force,
+                    skip_if_present: *skip_if_present,
+                    images: image.clone(),
+                    ..Default::default()
+                },
+                mode: if *test_only {
+                    FlashMode::Test
+                } else if *permanent {
+                    FlashMode::Permanent
+                } else {
+                    FlashMode::Confirm
+                },
+                timeout: *wait,
+            };
+            let v = session.flash(filename, &options, Some(progress_bar("upload complete")))?;
+            print!("response: {}", serde_json::to_string_pretty(&v)?);
+            Ok(())
+        }
+        Commands::Test {
+            hash,
+            file,
+            confirm,
+        } => {
+            let hashes = match (file, hash) {
+                (Some(file), _) => parse_images(file)?
+                    .iter()
+                    .map(|image| image_hash(&image.data))
+                    .collect::<Result<Vec<_>, _>>()?,
+                (None, Some(hash)) => vec![hex::decode(hash)?],
+                (None, None) => anyhow::bail!("hash or file required"),
+            };
+            for hash in hashes {
+                session.test(hash, *confirm)?;
+            }
+            Ok(())
+        }
+        // run by main, without opening the device
+        Commands::Image { .. } => unreachable!("image commands need no device"),
+        Commands::Erase { slot } => session.erase(*slot),
+        Commands::Echo { text } => {
+            println!("{}", session.echo(text)?);
+            Ok(())
+        }
+        Commands::ConsoleEcho { enable } => session.console_echo(*enable),
+        Commands::Taskstat => {
+            let v = session.taskstat()?;
+            print!("response: {}", serde_json::to_string_pretty(&v)?);
+            Ok(())
+        }
+        Commands::Mpstat => {
+            let v = session.mpstat()?;
+            print!("response: {}", serde_json::to_string_pretty(&v)?);
+            Ok(())
+        }
+        Commands::Datetime { value: None } => {
+            println!("{}", session.datetime()?);
+            Ok(())
+        }
+        Commands::Datetime { value: Some(value) } => session.set_datetime(value),
+        Commands::Params => {
+            let v = session.mcumgr_params()?;
+            print!("response: {}", serde_json::to_string_pretty(&v)?);
+            Ok(())
+        }
+        Commands::OsInfo { format } => {
+            println!("{}", session.os_info(format.as_deref())?);
+            Ok(())
+        }
+        Commands::Fs { command } => match command {
+            FsCommands::Upload { local, remote } => {
+                session.fs_upload(local, remote, Some(progress_bar("upload complete")))
+            }
+            FsCommands::Download { remote, local } => {
+                session.fs_download(remote, local, Some(progress_bar("download complete")))
+            }
+            FsCommands::Stat { remote } => {
+                println!("{}: {} bytes", remote, session.fs_stat(remote)?.len);
+                Ok(())
+            }
+            FsCommands::Hash { remote, hash_type } => {
+                let v = session.fs_hash(remote, hash_type.as_deref())?;
+                println!("{} {}: {}", v.hash_type, remote, v.output_hex());
+                Ok(())
+            }
+            FsCommands::Close => session.fs_close(),
+        },
+        Commands::Shell { command: None } => shell_interactive(session),
+        Commands::Shell {
+            command: Some(ShellCommands::Exec { argv }),
+        } => {
+            let rsp = session.shell_exec(argv)?;
+            print_output(&rsp.o);
+            if rsp.ret != 0 {
+                process::exit(rsp.ret);
+            }
+            Ok(())
+        }
+        Commands::Stat {
+            command: StatCommands::List,
+        } => {
+            for name in session.stat_list()? {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        Commands::Stat {
+            command: StatCommands::Read { name, watch: None },
+        } => {
+            let v = session.stat_read(name)?;
+            for (field, value) in &v.fields {
+                println!("{:>32}: {}", field, value);
+            }
+            Ok(())
+        }
+        Commands::Stat {
+            command:
+                StatCommands::Read {
+                    name,
+                    watch: Some(interval),
+                },
+        } => {
+            let mut previous = session.stat_read(name)?;
+            for (field, value) in &previous.fields {
+                println!("{:>32}: {}", field, value);
+            }
+            loop {
+                thread::sleep(*interval);
+                let current = session.stat_read(name)?;
+                println!("{}", humantime::format_rfc3339_seconds(SystemTime::now()));
+                for (field, delta) in current.deltas(&previous) {
+                    if delta != 0 {
+                        println!("{:>32}: {} ({:+})", field, current.fields[&field], delta);
+                    }
+                }
+                previous = current;
+            }
+        }
+        Commands::Settings { command } => match command {
+            SettingsCommands::Read { name } => {
+                let val = session.settings_read(name)?;
+                println!("hex: {}", hex::encode(&val));
+                if let Some(text) = printable_text(&val) {
+                    println!("text: {}", text);
+                }
+                Ok(())
+            }
+            SettingsCommands::Write {
+                name,
+                value,
+                hex,
+                file,
+            } => {
+                let val = match (file, value) {
+                    (Some(file), _) => std::fs::read(file)?,
+                    (None, Some(value)) if *hex => hex::decode(value)?,
+                    (None, Some(value)) => value.clone().into_bytes(),
+                    (None, None) => unreachable!("value or file is required"),
+                };
+                session.settings_write(name, &val)
+            }
+            SettingsCommands::Delete { name } => session.settings_delete(name),
+            SettingsCommands::Commit => session.settings_commit(),
+            SettingsCommands::Load => session.settings_load(),
+            SettingsCommands::Save => session.settings_save(),
+        },
+        Commands::Log { command } => match command {
+            LogCommands::Show {
+                name,
+                since,
+                follow,
+                json,
+            } => {
+                let mut index = *since;
+                loop {
+                    let v = session.log_show(name.as_deref(), index)?;
+                    for log in &v.logs {
+                        for entry in &log.entries {
+                            print_log_entry(&log.name, entry, *json)?;
+                        }
+                    }
+                    if !*follow {
+                        return Ok(());
+                    }
+                    index = Some(v.next_index);
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+            LogCommands::Clear => session.log_clear(),
+            LogCommands::List => {
+                for name in session.log_list()? {
+                    println!("{}", name);
+                }
+                Ok(())
+            }
+            LogCommands::Modules => {
+                for (name, id) in session.log_modules()? {
+                    println!("{:>4}: {}", id, name);
+                }
+                Ok(())
+            }
+            LogCommands::Levels => {
+                for (name, id) in session.log_levels()? {
+                    println!("{:>4}: {}", id, name);
+                }
+                Ok(())
+            }
+        },
+        Commands::Core { command } => match command {
+            CoreCommands::List => {
+                if session.core_list()? {
+                    println!("core dump present");
+                } else {
+                    println!("no core dump");
+                }
+                Ok(())
+            }
+            CoreCommands::Download { filename } => {
+                session.core_download(filename, Some(progress_bar("download complete")))
+            }
+            CoreCommands::Erase => session.core_erase(),
+        },
+        Commands::Run {
+            command: RunCommands::List,
+        } => {
+            for name in session.run_list()? {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        Commands::Run {
+            command: RunCommands::Test { suite, token, wait },
+        } => {
+            // remember where the log of this run starts, or read it all if it can't be
+            // shown yet, the token then tells this run apart
+            let index = match session.log_show(None, None) {
+                Ok(rsp) => Some(rsp.next_index),
+                Err(e) => {
+                    debug!("log show failed: {}", e);
+                    None
+                }
+            };
+            session.run_test(suite.as_deref(), token.as_deref())?;
+            let results = session.run_results(index, token.as_deref(), *wait)?;
+            if results.passed.is_empty() && results.failed.is_empty() {
+                anyhow::bail!("no test results found in the log");
+            }
+            for msg in results.passed.iter().chain(results.failed.iter()) {
+                println!("{}", msg);
+            }
+            println!(
+                "{} passed, {} failed",
+                results.passed.len(),
+                results.failed.len()
+            );
+            if !results.failed.is_empty() {
+                anyhow::bail!("{} tests failed", results.failed.len());
+            }
+            Ok(())
+        }
+        Commands::Crash { crash_type, wait } => {
+            session.crash(crash_type)?;
+            if let Some(timeout) = wait {
+                session.reconnect(*timeout)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// print the MCUboot header and TLVs of an image file
+fn image_info(filename: &Path) -> Result<(), Error> {
+    let images = parse_images(filename)?;
+    for (i, file) in images.iter().enumerate() {
+        if images.len() > 1 {
+            if i > 0 {
+                println!();
+            }
+            println!("file:          {}", file.file);
+        }
+        if let Some(index) = file.image_index {
+            println!("image index:   {}", index);
+        }
+        if let Some(slot) = file.slot {
+            println!("slot:          {}", slot);
+        }
+        if let Some(board) = &file.board {
+            println!("board:         {}", board);
+        }
+        let image = McubootImage::parse(&file.data)?;
+        let header = &image.header;
+        println!("load address:  0x{:08x}", header.load_addr);
+        println!("header size:   {}", header.hdr_size);
+        println!("image size:    {}", header.img_size);
+        println!("flags:         {}", header.flag_names().join(", "));
+        println!("version:       {}", header.version);
+        for tlv in &image.tlvs {
+            println!(
+                "TLV {:<12} {}{}",
+                tlv.type_name(),
+                hex::encode(&tlv.data),
+                if tlv.protected { " (protected)" } else { "" }
+            );
+        }
+        if let Some(counter) = image.security_counter() {
+            println!("security counter: {}", counter);
+        }
+        for dependency in image.dependencies() {
+            println!(
+                "depends on image {} >= {}",
+                dependency.image_id, dependency.version
+            );
+        }
+    }
+    Ok(())
+}
+
+// read commands line by line from stdin and run them on the device, until the end of the input
+fn shell_interactive(session: &mut Session) -> Result<(), Error> {
+    let mut lines = stdin().lock().lines();
+    loop {
+        print!("> ");
+        stdout().flush()?;
+
+        let Some(line) = lines.next() else {
+            println!();
+            return Ok(());
+        };
+        let argv: Vec<String> = line?.split_whitespace().map(str::to_string).collect();
+        if argv.is_empty() {
+            continue;
+        }
+
+        let rsp = session.shell_exec(&argv)?;
+        print_output(&rsp.o);
+        if rsp.ret != 0 {
+            println!("ret = {}", rsp.ret);
+        }
+    }
+}
+
+// print a log entry as text line, or as JSON line
+fn print_log_entry(log_name: &str, entry: &LogEntry, json: bool) -> Result<(), Error> {
+    if json {
+        let line = serde_json::json!({
+            "log": log_name,
+            "index": entry.index,
+            "ts": entry.ts,
+            "timestamp": entry.timestamp(),
+            "level": entry.level_name(),
+            "module": entry.module,
+            "msg": entry.message(),
+        });
+        println!("{}", serde_json::to_string(&line)?);
+    } else {
+        println!(
+            "{} [{}] {}/{}: {}",
+            entry.timestamp(),
+            entry.level_name(),
+            log_name,
+            entry.module,
+            entry.message()
+        );
+    }
+    Ok(())
+}
+
+// the value as text, if it is valid UTF-8 without control characters, ignoring a terminating NUL
+fn printable_text(val: &[u8]) -> Option<&str> {
+    let val = val.strip_suffix(&[0]).unwrap_or(val);
+    let text = std::str::from_utf8(val).ok()?;
+    if text
+        .chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t')
+    {
+        return None;
+    }
+    Some(text)
+}
+
+// print the output of a shell command, ending with a newline
+fn print_output(output: &str) {
+    print!("{}", output);
+    if !output.is_empty() && !output.ends_with('\n') {
+        println!();
+    }
+}
+
+// create a progress bar, updated by the returned progress callback
+fn progress_bar(message: &'static str) -> impl FnMut(u64, u64) {
+    let pb = ProgressBar::new(1);
+    pb.set_style(ProgressStyle::default_bar()
+    .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+    .unwrap().progress_chars("=> "));
+
+    move |offset, total| {
+        if let Some(l) = pb.length() {
+            if l != total {
+                pb.set_length(total)
+            }
+        }
+
+        pb.set_position(offset);
+
+        if offset >= total {
+            pb.finish_with_message(message);
+        }
+    }
+}
+
 fn main() {
     // parse command line arguments
     let mut cli = Cli::parse();
@@ -214,6 +967,18 @@ fn main() {
     )
     .unwrap_or_else(|_| SimpleLogger::init(LevelFilter::Info, Default::default()).unwrap());
 
+    // inspecting a file doesn't need a device
+    if let Commands::Image {
+        command: ImageCommands::Info { filename },
+    } = &cli.command
+    {
+        if let Err(e) = image_info(filename) {
+            error!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     // if no device is specified, try to auto detect it
     if cli.device.is_empty() {
         let vid: u16 = 12259;
@@ -268,44 +1033,8 @@ fn main() {
     let specs = SerialSpecs::from(&cli);
 
     // execute command
-    let result = match &cli.command {
-        Commands::List => || -> Result<(), Error> {
-            let v = list(&specs)?;
-            print!("response: {}", serde_json::to_string_pretty(&v)?);
-            Ok(())
-        }(),
-        Commands::Reset => reset(&specs),
-        Commands::Upload { filename, slot } => || -> Result<(), Error> {
-            // create a progress bar
-            let pb = ProgressBar::new(1 as u64);
-            pb.set_style(ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap().progress_chars("=> "));
-
-            upload(
-                &specs,
-                filename,
-                *slot,
-                Some(|offset, total| {
-                    if let Some(l) = pb.length() {
-                        if l != total {
-                            pb.set_length(total as u64)
-                        }
-                    }
-
-                    pb.set_position(offset as u64);
-
-                    if offset >= total {
-                        pb.finish_with_message("upload complete");
-                    }
-                }),
-            )
-        }(),
-        Commands::Test { hash, confirm } => {
-            || -> Result<(), Error> { test(&specs, hex::decode(hash)?, *confirm) }()
-        }
-        Commands::Erase { slot } => erase(&specs, *slot),
-    };
+    let result =
+        Session::open(&specs).and_then(|mut session| run_command(&mut session, &cli.command));
 
     // show error, if failed
     if let Err(e) = result {
@@ -0,0 +1,336 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, Error, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+
+pub const IMAGE_MAGIC: u32 = 0x96f3b83d;
+pub const IMAGE_HEADER_SIZE: usize = 32;
+pub const IMAGE_TLV_INFO_MAGIC: u16 = 0x6907;
+pub const IMAGE_TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+
+pub const IMAGE_TLV_KEYHASH: u16 = 0x01;
+pub const IMAGE_TLV_PUBKEY: u16 = 0x02;
+pub const IMAGE_TLV_SHA256: u16 = 0x10;
+pub const IMAGE_TLV_SHA384: u16 = 0x11;
+pub const IMAGE_TLV_SHA512: u16 = 0x12;
+pub const IMAGE_TLV_RSA2048_PSS: u16 = 0x20;
+pub const IMAGE_TLV_ECDSA224: u16 = 0x21;
+pub const IMAGE_TLV_ECDSA_SIG: u16 = 0x22;
+pub const IMAGE_TLV_RSA3072_PSS: u16 = 0x23;
+pub const IMAGE_TLV_ED25519: u16 = 0x24;
+pub const IMAGE_TLV_SIG_PURE: u16 = 0x25;
+pub const IMAGE_TLV_ENC_RSA2048: u16 = 0x30;
+pub const IMAGE_TLV_ENC_KW: u16 = 0x31;
+pub const IMAGE_TLV_ENC_EC256: u16 = 0x32;
+pub const IMAGE_TLV_ENC_X25519: u16 = 0x33;
+pub const IMAGE_TLV_DEPENDENCY: u16 = 0x40;
+pub const IMAGE_TLV_SEC_CNT: u16 = 0x50;
+pub const IMAGE_TLV_BOOT_RECORD: u16 = 0x60;
+
+/// Names of the image header flags, by bit.
+const IMAGE_FLAGS: [(u32, &str); 6] = [
+    (0x01, "PIC"),
+    (0x04, "ENCRYPTED_AES128"),
+    (0x08, "ENCRYPTED_AES256"),
+    (0x10, "NON_BOOTABLE"),
+    (0x20, "RAM_LOAD"),
+    (0x100, "ROM_FIXED"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build_num: u32,
+}
+
+impl ImageVersion {
+    fn parse(data: &[u8]) -> ImageVersion {
+        ImageVersion {
+            major: data[0],
+            minor: data[1],
+            revision: LittleEndian::read_u16(&data[2..4]),
+            build_num: LittleEndian::read_u32(&data[4..8]),
+        }
+    }
+}
+
+impl fmt::Display for ImageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}+{}",
+            self.major, self.minor, self.revision, self.build_num
+        )
+    }
+}
+
+/// The header at the start of an MCUboot image.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageHeader {
+    pub load_addr: u32,
+    pub hdr_size: u16,
+    pub protect_tlv_size: u16,
+    pub img_size: u32,
+    pub flags: u32,
+    pub version: ImageVersion,
+}
+
+impl ImageHeader {
+    /// Parse the image header, failing if the data does not start with the MCUboot magic.
+    pub fn parse(data: &[u8]) -> Result<ImageHeader, Error> {
+        if data.len() < IMAGE_HEADER_SIZE {
+            bail!("image too short for an MCUboot header");
+        }
+        let magic = LittleEndian::read_u32(&data[0..4]);
+        if magic != IMAGE_MAGIC {
+            bail!("no MCUboot image magic, found 0x{:08x}", magic);
+        }
+        Ok(ImageHeader {
+            load_addr: LittleEndian::read_u32(&data[4..8]),
+            hdr_size: LittleEndian::read_u16(&data[8..10]),
+            protect_tlv_size: LittleEndian::read_u16(&data[10..12]),
+            img_size: LittleEndian::read_u32(&data[12..16]),
+            flags: LittleEndian::read_u32(&data[16..20]),
+            version: ImageVersion::parse(&data[20..28]),
+        })
+    }
+
+    /// Names of the set flags.
+    pub fn flag_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = IMAGE_FLAGS
+            .iter()
+            .filter(|(bit, _)| self.flags & bit != 0)
+            .map(|(_, name)| name.to_string())
+            .collect();
+        let known = IMAGE_FLAGS.iter().fold(0, |acc, (bit, _)| acc | bit);
+        if self.flags & !known != 0 {
+            names.push(format!("0x{:x}", self.flags & !known));
+        }
+        names
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageTlv {
+    pub tlv_type: u16,
+    pub data: Vec<u8>,
+    /// Covered by the signature, part of the protected TLV area
+    pub protected: bool,
+}
+
+impl ImageTlv {
+    /// Name of the TLV type, as in MCUboot's image.h.
+    pub fn type_name(&self) -> String {
+        match self.tlv_type {
+            IMAGE_TLV_KEYHASH => "KEYHASH".to_string(),
+            IMAGE_TLV_PUBKEY => "PUBKEY".to_string(),
+            IMAGE_TLV_SHA256 => "SHA256".to_string(),
+            IMAGE_TLV_SHA384 => "SHA384".to_string(),
+            IMAGE_TLV_SHA512 => "SHA512".to_string(),
+            IMAGE_TLV_RSA2048_PSS => "RSA2048_PSS".to_string(),
+            IMAGE_TLV_ECDSA224 => "ECDSA224".to_string(),
+            IMAGE_TLV_ECDSA_SIG => "ECDSA_SIG".to_string(),
+            IMAGE_TLV_RSA3072_PSS => "RSA3072_PSS".to_string(),
+            IMAGE_TLV_ED25519 => "ED25519".to_string(),
+            IMAGE_TLV_SIG_PURE => "SIG_PURE".to_string(),
+            IMAGE_TLV_ENC_RSA2048 => "ENC_RSA2048".to_string(),
+            IMAGE_TLV_ENC_KW => "ENC_KW".to_string(),
+            IMAGE_TLV_ENC_EC256 => "ENC_EC256".to_string(),
+            IMAGE_TLV_ENC_X25519 => "ENC_X25519".to_string(),
+            IMAGE_TLV_DEPENDENCY => "DEPENDENCY".to_string(),
+            IMAGE_TLV_SEC_CNT => "SEC_CNT".to_string(),
+            IMAGE_TLV_BOOT_RECORD => "BOOT_RECORD".to_string(),
+            other => format!("0x{:02x}", other),
+        }
+    }
+}
+
+/// A dependency of an image on the minimum version of another image.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageDependency {
+    pub image_id: u8,
+    pub version: ImageVersion,
+}
+
+/// A parsed MCUboot image: its header and the TLVs after the image data.
+#[derive(Debug, Clone)]
+pub struct McubootImage {
+    pub header: ImageHeader,
+    pub tlvs: Vec<ImageTlv>,
+}
+
+impl McubootImage {
+    /// Parse the header and the protected and unprotected TLV areas of an image.
+    pub fn parse(data: &[u8]) -> Result<McubootImage, Error> {
+        let header = ImageHeader::parse(data)?;
+        let mut off = header.hdr_size as usize + header.img_size as usize;
+        let mut tlvs = Vec::new();
+
+        if header.protect_tlv_size > 0 {
+            let len = parse_tlv_area(data, off, IMAGE_TLV_PROT_INFO_MAGIC, true, &mut tlvs)?;
+            if len != header.protect_tlv_size as usize {
+                bail!("protected TLV area size does not match the header");
+            }
+            off += len;
+        }
+        parse_tlv_area(data, off, IMAGE_TLV_INFO_MAGIC, false, &mut tlvs)?;
+
+        Ok(McubootImage { header, tlvs })
+    }
+
+    /// The first TLV of the given type.
+    pub fn tlv(&self, tlv_type: u16) -> Option<&ImageTlv> {
+        self.tlvs.iter().find(|tlv| tlv.tlv_type == tlv_type)
+    }
+
+    /// The SHA256 hash of the image, which the device reports in its image list.
+    pub fn sha256(&self) -> Option<&[u8]> {
+        self.tlv(IMAGE_TLV_SHA256).map(|tlv| tlv.data.as_slice())
+    }
+
+    pub fn security_counter(&self) -> Option<u32> {
+        self.tlv(IMAGE_TLV_SEC_CNT)
+            .filter(|tlv| tlv.data.len() == 4)
+            .map(|tlv| LittleEndian::read_u32(&tlv.data))
+    }
+
+    pub fn dependencies(&self) -> Vec<ImageDependency> {
+        self.tlvs
+            .iter()
+            .filter(|tlv| tlv.tlv_type == IMAGE_TLV_DEPENDENCY && tlv.data.len() == 12)
+            .map(|tlv| ImageDependency {
+                image_id: tlv.data[0],
+                version: ImageVersion::parse(&tlv.data[4..12]),
+            })
+            .collect()
+    }
+}
+
+/// The SHA256 hash of an MCUboot image, as `list` shows it and `test` expects it.
+pub fn image_hash(data: &[u8]) -> Result<Vec<u8>, Error> {
+    match McubootImage::parse(data)?.sha256() {
+        Some(hash) => Ok(hash.to_vec()),
+        None => bail!("image has no SHA256 TLV"),
+    }
+}
+
+/// Parse the TLV area at `off`, append its TLVs and return its total length.
+fn parse_tlv_area(
+    data: &[u8],
+    off: usize,
+    magic: u16,
+    protected: bool,
+    tlvs: &mut Vec<ImageTlv>,
+) -> Result<usize, Error> {
+    if off + 4 > data.len() {
+        bail!("TLV area missing after the image data");
+    }
+    let read_magic = LittleEndian::read_u16(&data[off..off + 2]);
+    if read_magic != magic {
+        bail!(
+            "wrong TLV area magic, expected: 0x{:04x}, read: 0x{:04x}",
+            magic,
+            read_magic
+        );
+    }
+    let tlv_tot = LittleEndian::read_u16(&data[off + 2..off + 4]) as usize;
+    let end = off + tlv_tot;
+    if tlv_tot < 4 || end > data.len() {
+        bail!("TLV area exceeds the image");
+    }
+
+    let mut pos = off + 4;
+    while pos < end {
+        if pos + 4 > end {
+            bail!("truncated TLV at offset {}", pos);
+        }
+        let tlv_type = LittleEndian::read_u16(&data[pos..pos + 2]);
+        let len = LittleEndian::read_u16(&data[pos + 2..pos + 4]) as usize;
+        pos += 4;
+        if pos + len > end {
+            bail!("truncated TLV at offset {}", pos - 4);
+        }
+        tlvs.push(ImageTlv {
+            tlv_type,
+            data: data[pos..pos + len].to_vec(),
+            protected,
+        });
+        pos += len;
+    }
+
+    Ok(tlv_tot)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn push_tlv(area: &mut Vec<u8>, tlv_type: u16, data: &[u8]) {
+        area.write_u16::<LittleEndian>(tlv_type).unwrap();
+        area.write_u16::<LittleEndian>(data.len() as u16).unwrap();
+        area.extend_from_slice(data);
+    }
+
+    fn push_area(image: &mut Vec<u8>, magic: u16, tlvs: &[u8]) {
+        image.write_u16::<LittleEndian>(magic).unwrap();
+        image
+            .write_u16::<LittleEndian>(tlvs.len() as u16 + 4)
+            .unwrap();
+        image.extend_from_slice(tlvs);
+    }
+
+    /// A small image with a security counter in the protected area and a SHA256 TLV.
+    pub(crate) fn test_image(payload: &[u8], sha256: &[u8]) -> Vec<u8> {
+        let mut protected = Vec::new();
+        push_tlv(&mut protected, IMAGE_TLV_SEC_CNT, &7u32.to_le_bytes());
+
+        let mut image = Vec::new();
+        image.write_u32::<LittleEndian>(IMAGE_MAGIC).unwrap();
+        image.write_u32::<LittleEndian>(0).unwrap();
+        image.write_u16::<LittleEndian>(32).unwrap();
+        image
+            .write_u16::<LittleEndian>(protected.len() as u16 + 4)
+            .unwrap();
+        image
+            .write_u32::<LittleEndian>(payload.len() as u32)
+            .unwrap();
+        image.write_u32::<LittleEndian>(0x10).unwrap();
+        image.extend_from_slice(&[1, 2]);
+        image.write_u16::<LittleEndian>(3).unwrap();
+        image.write_u32::<LittleEndian>(4).unwrap();
+        image.write_u32::<LittleEndian>(0).unwrap();
+        image.extend_from_slice(payload);
+
+        push_area(&mut image, IMAGE_TLV_PROT_INFO_MAGIC, &protected);
+        let mut unprotected = Vec::new();
+        push_tlv(&mut unprotected, IMAGE_TLV_SHA256, sha256);
+        push_area(&mut image, IMAGE_TLV_INFO_MAGIC, &unprotected);
+        image
+    }
+
+    #[test]
+    fn parse_image_header_and_tlvs() {
+        let image = McubootImage::parse(&test_image(&[0xaa; 100], &[0x55; 32])).unwrap();
+        assert_eq!(image.header.img_size, 100);
+        assert_eq!(image.header.version.to_string(), "1.2.3+4");
+        assert_eq!(image.header.flag_names(), vec!["NON_BOOTABLE"]);
+        assert_eq!(image.security_counter(), Some(7));
+        assert!(image.tlv(IMAGE_TLV_SEC_CNT).unwrap().protected);
+        assert_eq!(image.sha256(), Some(&[0x55; 32][..]));
+    }
+
+    #[test]
+    fn hash_from_tlv() {
+        let hash: Vec<u8> = (0..32).collect();
+        assert_eq!(image_hash(&test_image(&[1, 2, 3], &hash)).unwrap(), hash);
+    }
+
+    #[test]
+    fn reject_missing_magic() {
+        assert!(McubootImage::parse(&[0u8; 64]).is_err());
+    }
+}
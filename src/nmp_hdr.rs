@@ -2,9 +2,9 @@
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use hex_buffer_serde::{Hex as _, HexForm};
-use num;
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io::Cursor;
 
 #[repr(u8)]
@@ -16,9 +16,9 @@ pub enum NmpOp {
     WriteRsp = 3,
 }
 
-#[repr(u8)]
-#[derive(Debug, Copy, Clone)]
-#[allow(dead_code)]
+/// Generic MCUmgr result codes (MGMT_ERR), the only codes of SMP v1.
+#[repr(u16)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
 pub enum NmpErr {
     Ok = 0,
     EUnknown = 1,
@@ -26,22 +26,134 @@ pub enum NmpErr {
     EInvalid = 3,
     ETimeout = 4,
     ENoEnt = 5,
+    EBadState = 6,
+    EMsgSize = 7,
+    ENotSup = 8,
+    ECorrupt = 9,
+    EBusy = 10,
+    EAccessDenied = 11,
+    UnsupportedTooOld = 12,
+    UnsupportedTooNew = 13,
+    EPerUser = 256,
 }
 
+/// Result codes of the image group (IMG_MGMT_ERR) in SMP v2.
 #[repr(u16)]
-#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+pub enum NmpImageErr {
+    Ok = 0,
+    Unknown = 1,
+    FlashConfigQueryFail = 2,
+    NoImage = 3,
+    NoTlvs = 4,
+    InvalidTlv = 5,
+    TlvMultipleHashesFound = 6,
+    TlvInvalidSize = 7,
+    HashNotFound = 8,
+    NoFreeSlot = 9,
+    FlashOpenFailed = 10,
+    FlashReadFailed = 11,
+    FlashWriteFailed = 12,
+    FlashEraseFailed = 13,
+    InvalidSlot = 14,
+    NoFreeMemory = 15,
+    FlashContextAlreadySet = 16,
+    FlashContextNotSet = 17,
+    FlashAreaDeviceNull = 18,
+    InvalidPageOffset = 19,
+    InvalidOffset = 20,
+    InvalidLength = 21,
+    InvalidImageHeader = 22,
+    InvalidImageHeaderMagic = 23,
+    InvalidHash = 24,
+    InvalidFlashAddress = 25,
+    VersionGetFailed = 26,
+    CurrentVersionIsNewer = 27,
+    ImageAlreadyPending = 28,
+    InvalidImageVectorTable = 29,
+    InvalidImageTooLarge = 30,
+    InvalidImageDataOverrun = 31,
+    ImageConfirmationDenied = 32,
+    ImageSettingTestToActiveDenied = 33,
+    ActiveSlotNotKnown = 34,
+}
+
+/// Result codes of the file system group (FS_MGMT_ERR) in SMP v2.
+#[repr(u16)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+pub enum NmpFsErr {
+    Ok = 0,
+    Unknown = 1,
+    FileInvalidName = 2,
+    FileNotFound = 3,
+    FileIsDirectory = 4,
+    FileOpenFailed = 5,
+    FileSeekFailed = 6,
+    FileReadFailed = 7,
+    FileTruncateFailed = 8,
+    FileDeleteFailed = 9,
+    FileWriteFailed = 10,
+    FileOffsetNotValid = 11,
+    FileOffsetLargerThanFile = 12,
+    ChecksumHashNotFound = 13,
+    MountPointNotFound = 14,
+    ReadOnlyFilesystem = 15,
+    FileEmpty = 16,
+}
+
+/// Version of the protocol, in bits 3 and 4 of the op byte.
+pub const SMP_VERSION_1: u8 = 0;
+/// SMP v2 reports errors as `err: {group, rc}` with group specific codes.
+pub const SMP_VERSION_2: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum NmpGroup {
-    Default = 0,
-    Image = 1,
-    Stat = 2,
-    Config = 3,
-    Log = 4,
-    Crash = 5,
-    Split = 6,
-    Run = 7,
-    Fs = 8,
-    Shell = 9,
-    PerUser = 64,
+    Default,
+    Image,
+    Stat,
+    Config,
+    Log,
+    Crash,
+    Split,
+    Run,
+    Fs,
+    Shell,
+    /// Any other group, like Zephyr's basic group 63, or the user groups from 64 on
+    PerUser(u16),
+}
+
+impl NmpGroup {
+    pub fn from_u16(group: u16) -> NmpGroup {
+        match group {
+            0 => NmpGroup::Default,
+            1 => NmpGroup::Image,
+            2 => NmpGroup::Stat,
+            3 => NmpGroup::Config,
+            4 => NmpGroup::Log,
+            5 => NmpGroup::Crash,
+            6 => NmpGroup::Split,
+            7 => NmpGroup::Run,
+            8 => NmpGroup::Fs,
+            9 => NmpGroup::Shell,
+            other => NmpGroup::PerUser(other),
+        }
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            NmpGroup::Default => 0,
+            NmpGroup::Image => 1,
+            NmpGroup::Stat => 2,
+            NmpGroup::Config => 3,
+            NmpGroup::Log => 4,
+            NmpGroup::Crash => 5,
+            NmpGroup::Split => 6,
+            NmpGroup::Run => 7,
+            NmpGroup::Fs => 8,
+            NmpGroup::Shell => 9,
+            NmpGroup::PerUser(group) => *group,
+        }
+    }
 }
 
 pub trait NmpId {
@@ -58,6 +170,8 @@ pub enum NmpIdDef {
     MpStat = 3,
     DateTimeStr = 4,
     Reset = 5,
+    McumgrParams = 6,
+    Info = 7,
 }
 
 impl NmpId for NmpIdDef {
@@ -91,11 +205,26 @@ pub enum NmpIdStat {
     List = 1,
 }
 
+impl NmpId for NmpIdStat {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
 pub enum NmpIdConfig {
     Val = 0,
+    Delete = 1,
+    Commit = 2,
+    LoadSave = 3,
+}
+
+impl NmpId for NmpIdConfig {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
 }
 
 #[repr(u8)]
@@ -110,6 +239,12 @@ pub enum NmpIdLog {
     List = 5,
 }
 
+impl NmpId for NmpIdLog {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
@@ -117,6 +252,12 @@ pub enum NmpIdCrash {
     Trigger = 0,
 }
 
+impl NmpId for NmpIdCrash {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
@@ -125,11 +266,27 @@ pub enum NmpIdRun {
     List = 1,
 }
 
+impl NmpId for NmpIdRun {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
 pub enum NmpIdFs {
     File = 0,
+    Status = 1,
+    HashChecksum = 2,
+    SupportedHashChecksumTypes = 3,
+    Close = 4,
+}
+
+impl NmpId for NmpIdFs {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
 }
 
 #[repr(u8)]
@@ -139,8 +296,15 @@ pub enum NmpIdShell {
     Exec = 0,
 }
 
+impl NmpId for NmpIdShell {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub struct NmpHdr {
+    pub version: u8,
     pub op: NmpOp,
     pub flags: u8,
     pub len: u16,
@@ -152,6 +316,7 @@ pub struct NmpHdr {
 impl NmpHdr {
     pub fn new_req(op: NmpOp, group: NmpGroup, id: impl NmpId) -> NmpHdr {
         NmpHdr {
+            version: SMP_VERSION_1,
             op,
             flags: 0,
             len: 0,
@@ -163,23 +328,32 @@ impl NmpHdr {
 
     pub fn serialize(&self) -> Result<Vec<u8>, bincode::Error> {
         let mut buffer = Vec::new();
-        buffer.write_u8(self.op as u8)?;
+        buffer.write_u8((self.version << 3) | self.op as u8)?;
         buffer.write_u8(self.flags)?;
         buffer.write_u16::<BigEndian>(self.len)?;
-        buffer.write_u16::<BigEndian>(self.group as u16)?;
+        buffer.write_u16::<BigEndian>(self.group.to_u16())?;
         buffer.write_u8(self.seq)?;
         buffer.write_u8(self.id)?;
         Ok(buffer)
     }
 
-    pub fn deserialize(cursor: &mut Cursor<&Vec<u8>>) -> Result<NmpHdr, bincode::Error> {
-        let op = num::FromPrimitive::from_u8(cursor.read_u8()?).unwrap();
+    /// Read a header, failing on truncated input and unknown ops.
+    pub fn deserialize(cursor: &mut Cursor<&[u8]>) -> Result<NmpHdr, bincode::Error> {
+        let op_byte = cursor.read_u8()?;
+        let version = (op_byte >> 3) & 0x03;
+        let Some(op) = num::FromPrimitive::from_u8(op_byte & 0x07) else {
+            return Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "unknown op {}",
+                op_byte & 0x07
+            ))));
+        };
         let flags = cursor.read_u8()?;
         let len = cursor.read_u16::<BigEndian>()?;
-        let group = num::FromPrimitive::from_u16(cursor.read_u16::<BigEndian>()?).unwrap();
+        let group = NmpGroup::from_u16(cursor.read_u16::<BigEndian>()?);
         let seq = cursor.read_u8()?;
         let id = cursor.read_u8()?;
         Ok(NmpHdr {
+            version,
             op,
             flags,
             len,
@@ -191,6 +365,7 @@ impl NmpHdr {
 }
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[allow(dead_code)]
 pub struct NmpBase {
     pub hdr: NmpHdr,
 }
@@ -275,3 +450,294 @@ pub struct ImageEraseReq {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub slot: Option<u32>,
 }
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CoreLoadReq {
+    pub off: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CoreLoadRsp {
+    pub off: u32,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub len: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EchoReq {
+    pub d: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EchoRsp {
+    pub r: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConsEchoCtrlReq {
+    pub echo: u32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TaskStatEntry {
+    #[serde(default)]
+    pub prio: u32,
+    #[serde(default)]
+    pub tid: u32,
+    #[serde(default)]
+    pub state: u32,
+    #[serde(default)]
+    pub stkuse: u32,
+    #[serde(default)]
+    pub stksiz: u32,
+    #[serde(default)]
+    pub cswcnt: u32,
+    #[serde(default)]
+    pub runtime: u64,
+    #[serde(default)]
+    pub last_checkin: u32,
+    #[serde(default)]
+    pub next_checkin: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TaskStatRsp {
+    pub tasks: BTreeMap<String, TaskStatEntry>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct MpStatEntry {
+    #[serde(default)]
+    pub blksiz: u32,
+    #[serde(default)]
+    pub nblks: u32,
+    #[serde(default)]
+    pub nfree: u32,
+    #[serde(default)]
+    pub min: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MpStatRsp {
+    pub mpools: BTreeMap<String, MpStatEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DateTimeReq {
+    pub datetime: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DateTimeRsp {
+    pub datetime: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct McumgrParamsRsp {
+    pub buf_size: u32,
+    pub buf_count: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OsInfoReq {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OsInfoRsp {
+    pub output: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FsUploadReq {
+    pub name: String,
+    pub off: u64,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub len: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsUploadRsp {
+    pub off: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsDownloadReq {
+    pub name: String,
+    pub off: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsDownloadRsp {
+    pub off: u64,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub len: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsStatusReq {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsStatusRsp {
+    pub len: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsHashReq {
+    pub name: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub hash_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub off: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub len: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsHashRsp {
+    #[serde(rename = "type")]
+    pub hash_type: String,
+    #[serde(default)]
+    pub off: u64,
+    pub len: u64,
+    /// An integer for checksums like crc32, a byte string for hashes like sha256
+    pub output: serde_cbor::Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShellExecReq {
+    pub argv: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShellExecRsp {
+    pub o: String,
+    #[serde(default)]
+    pub ret: i32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StatListRsp {
+    pub stat_list: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StatReadReq {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StatReadRsp {
+    pub name: String,
+    pub fields: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SettingsReadReq {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SettingsReadRsp {
+    #[serde(with = "serde_bytes")]
+    pub val: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SettingsWriteReq {
+    pub name: String,
+    #[serde(with = "serde_bytes")]
+    pub val: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SettingsDeleteReq {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogShowReq {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogEntry {
+    /// A text string, or a byte string for binary and CBOR logs
+    pub msg: serde_cbor::Value,
+    /// Microseconds since the epoch, or since boot on devices without clock
+    #[serde(default)]
+    pub ts: i64,
+    #[serde(default)]
+    pub level: u8,
+    #[serde(default)]
+    pub index: u32,
+    #[serde(default)]
+    pub module: u8,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub entry_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogShowLog {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub log_type: u32,
+    #[serde(default)]
+    pub entries: Vec<LogEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogShowRsp {
+    #[serde(default)]
+    pub next_index: u32,
+    #[serde(default)]
+    pub logs: Vec<LogShowLog>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogListRsp {
+    pub log_list: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogModuleListRsp {
+    pub module_map: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogLevelListRsp {
+    pub level_map: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CrashTriggerReq {
+    pub t: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunTestReq {
+    pub testname: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunListRsp {
+    pub run_list: Vec<String>,
+}
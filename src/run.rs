@@ -0,0 +1,134 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Context, Error, Result};
+use log::{debug, info};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::nmp_hdr::*;
+use crate::session::check_rc;
+use crate::session::empty_body;
+use crate::session::Session;
+
+/// Outcome of on-device tests, collected from the log.
+#[derive(Debug, Clone, Default)]
+pub struct RunResults {
+    /// Log messages of passed tests
+    pub passed: Vec<String>,
+    /// Log messages of failed tests
+    pub failed: Vec<String>,
+}
+
+impl Session {
+    /// List the test suites of the device.
+    pub fn run_list(&mut self) -> Result<Vec<String>, Error> {
+        info!("send run list request");
+
+        let body = empty_body()?;
+        let rsp: RunListRsp =
+            self.request_rsp(NmpOp::Read, NmpGroup::Run, NmpIdRun::List, &body)?;
+
+        Ok(rsp.run_list)
+    }
+
+    /// Start a test suite, or all suites without name. The device adds the token to the
+    /// log messages of this run.
+    pub fn run_test(&mut self, testname: Option<&str>, token: Option<&str>) -> Result<(), Error> {
+        info!("send run test request");
+
+        let req = RunTestReq {
+            testname: testname.unwrap_or("all").to_string(),
+            token: token.map(str::to_string),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        let (_, response_body) =
+            self.request(NmpOp::Write, NmpGroup::Run, NmpIdRun::Test, &body)?;
+        check_rc(&response_body)
+    }
+
+    /// Collect test results from the log entries starting at `index`, or the whole log
+    /// without it, until an entry with "[done]" ends the run or the given time is over.
+    ///
+    /// Entries with "[pass]" or "[fail]" in their message count as test results, and with a
+    /// token, only entries containing it are considered.
+    pub fn run_results(
+        &mut self,
+        index: Option<u32>,
+        token: Option<&str>,
+        duration: Duration,
+    ) -> Result<RunResults, Error> {
+        let mut results = RunResults::default();
+        let mut index = index;
+        let start_time = Instant::now();
+        loop {
+            let rsp = self
+                .log_show(None, index)
+                .context("failed to read the test results from the log")?;
+            let mut done = false;
+            for entry in rsp.logs.iter().flat_map(|log| log.entries.iter()) {
+                let msg = entry.message();
+                if token.is_some_and(|token| !msg.contains(token)) {
+                    continue;
+                }
+                let lowercase = msg.to_lowercase();
+                if lowercase.contains("[done]") {
+                    done = true;
+                    break;
+                }
+                if lowercase.contains("[fail]") {
+                    results.failed.push(msg);
+                } else if lowercase.contains("[pass]") {
+                    results.passed.push(msg);
+                }
+            }
+            debug!(
+                "{} passed, {} failed",
+                results.passed.len(),
+                results.failed.len()
+            );
+            index = Some(rsp.next_index);
+
+            if done || start_time.elapsed() >= duration {
+                return Ok(results);
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sim::SimOptions;
+    use crate::test_util::{sim_session, test_specs};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn collect_run_results() {
+        let mut session = sim_session(test_specs(), SimOptions::default());
+        assert_eq!(session.run_list().unwrap(), ["kernel", "flash"]);
+
+        session.run_test(Some("kernel"), Some("ci-1")).unwrap();
+        let index = session.log_show(None, None).unwrap().next_index;
+        session.run_test(None, Some("ci-2")).unwrap();
+
+        // only results after the index, and with the token, count
+        let results = session
+            .run_results(Some(index), Some("ci-2"), Duration::ZERO)
+            .unwrap();
+        assert_eq!(results.passed.len(), 3);
+        assert_eq!(results.failed, ["[fail] flash.write ci-2"]);
+
+        // the end of the first run stops the collection before the second one
+        let results = session.run_results(None, None, Duration::ZERO).unwrap();
+        assert_eq!((results.passed.len(), results.failed.len()), (2, 0));
+
+        // a finished run returns right away instead of waiting
+        let start = Instant::now();
+        let results = session
+            .run_results(None, Some("ci-2"), Duration::from_secs(30))
+            .unwrap();
+        assert_eq!((results.passed.len(), results.failed.len()), (3, 1));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(session.run_test(Some("none"), None).is_err());
+    }
+}
@@ -0,0 +1,426 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, Error, Result};
+use log::info;
+use serde::de::DeserializeOwned;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{DeviceError, SmpError, TransportError};
+use crate::nmp_hdr::*;
+use crate::transfer::decode_frame;
+use crate::transfer::encode_versioned_frame;
+use crate::transfer::is_timeout;
+use crate::transfer::next_seq_id;
+use crate::transfer::open_transport;
+use crate::transfer::ClosedTransport;
+use crate::transfer::SerialSpecs;
+use crate::transfer::SmpTransport;
+
+/// An open connection to a device, shared by all commands sent to it.
+///
+/// The transport is opened once and kept until the session is dropped, so several
+/// commands can be run without the device re-enumerating in between.
+pub struct Session {
+    pub(crate) specs: SerialSpecs,
+    pub(crate) transport: Box<dyn SmpTransport>,
+    seq: u8,
+    version: u8,
+}
+
+impl Session {
+    pub fn open(specs: &SerialSpecs) -> Result<Session, Error> {
+        let transport = open_transport(specs)?;
+        Ok(Session::with_transport(specs, transport))
+    }
+
+    /// Create a session on top of an already opened transport.
+    pub fn with_transport(specs: &SerialSpecs, transport: Box<dyn SmpTransport>) -> Session {
+        Session {
+            specs: specs.clone(),
+            transport,
+            seq: next_seq_id(),
+            version: SMP_VERSION_2,
+        }
+    }
+
+    pub fn specs(&self) -> &SerialSpecs {
+        &self.specs
+    }
+
+    /// SMP version of the requests, v2 until the device turns out to speak v1 only.
+    pub fn smp_version(&self) -> u8 {
+        self.version
+    }
+
+    /// Open the transport again and wait until the device answers, e.g. after a reset.
+    pub fn reconnect(&mut self, timeout: Duration) -> Result<(), Error> {
+        info!("waiting for the device");
+
+        let start_time = Instant::now();
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            if start_time.elapsed() > timeout {
+                bail!(
+                    "device did not answer within {}",
+                    humantime::format_duration(timeout)
+                );
+            }
+
+            // the port can be gone while the device re-enumerates, and the old one has to
+            // be closed first, as a serial port can only be opened once
+            self.transport = Box::new(ClosedTransport);
+            match open_transport(&self.specs) {
+                Ok(transport) => self.transport = transport,
+                Err(e) => {
+                    log::debug!("open failed: {}", e);
+                    continue;
+                }
+            }
+
+            // any request the bootloader and the application both answer will do
+            self.transport.set_timeout(Duration::from_secs(1))?;
+            let body = empty_body()?;
+            match self.request(NmpOp::Read, NmpGroup::Image, NmpIdImage::State, &body) {
+                Ok(_) => break,
+                Err(e) => log::debug!("no answer: {}", e),
+            }
+        }
+
+        self.set_initial_timeout()?;
+        info!("device is back");
+        Ok(())
+    }
+
+    pub(crate) fn set_initial_timeout(&mut self) -> Result<(), Error> {
+        self.transport
+            .set_timeout(Duration::from_secs(self.specs.initial_timeout_s as u64))
+    }
+
+    /// Lower the timeout once the first request of a transfer is answered. The first one
+    /// can take long, e.g. while the device erases its flash, the later ones are answered
+    /// quickly, so a lost one is sent again soon.
+    pub(crate) fn set_subsequent_timeout(&mut self) -> Result<(), Error> {
+        self.transport.set_timeout(Duration::from_millis(
+            self.specs.subsequent_timeout_ms as u64,
+        ))
+    }
+
+    /// Run a transfer which lowers the timeout after its first answer. The first request
+    /// gets the initial timeout, and the timeout is restored when the transfer ends.
+    pub(crate) fn with_initial_timeout<T>(
+        &mut self,
+        transfer: impl FnOnce(&mut Session) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.set_initial_timeout()?;
+        let result = transfer(self);
+        let restored = self.set_initial_timeout();
+        let value = result?;
+        restored?;
+        Ok(value)
+    }
+
+    pub(crate) fn next_seq(&mut self) -> u8 {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        seq
+    }
+
+    /// Build a request frame with the SMP version of this session.
+    pub(crate) fn encode_request(
+        &self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: impl NmpId,
+        body: &[u8],
+        seq_id: u8,
+    ) -> Result<(Vec<u8>, NmpHdr), Error> {
+        encode_versioned_frame(self.version, op, group, id, body, seq_id)
+    }
+
+    /// Fall back to SMP v1 for all later requests, if the device does not speak v2.
+    /// Returns true if the device refused the request, which then has to be sent again.
+    pub(crate) fn version_fallback(
+        &mut self,
+        request_header: &NmpHdr,
+        response_header: &NmpHdr,
+        response_body: &serde_cbor::Value,
+    ) -> bool {
+        if request_header.version == SMP_VERSION_1 {
+            return false;
+        }
+
+        // legacy devices ignore the version bits and answer in v1, newer ones refuse v2
+        // if it is disabled
+        let refused = DeviceError::from_body(response_body)
+            == Some(DeviceError::Mgmt(NmpErr::UnsupportedTooNew));
+        if refused || response_header.version < request_header.version {
+            if self.version != SMP_VERSION_1 {
+                log::debug!("device speaks SMP v1 only");
+            }
+            self.version = SMP_VERSION_1;
+        }
+        refused
+    }
+
+    /// Send a request frame and return the answer to it.
+    ///
+    /// Answers to earlier requests are skipped: after a timeout, the answer to the first
+    /// attempt can still arrive and would be taken for the answer to the next request.
+    pub(crate) fn transceive(
+        &mut self,
+        frame: &[u8],
+        request_header: &NmpHdr,
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        let mut response = self.transport.transceive(frame)?;
+        loop {
+            let (response_header, response_body) = decode_frame(&response)?;
+            let behind = request_header.seq.wrapping_sub(response_header.seq);
+            if behind == 0 || behind > u8::MAX / 2 {
+                return Ok((response_header, response_body));
+            }
+            log::debug!(
+                "dropping late answer with sequence number {}",
+                response_header.seq
+            );
+            response = self.transport.recv()?;
+        }
+    }
+
+    /// Send a single request and return the verified response.
+    pub(crate) fn request(
+        &mut self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: impl NmpId + Copy,
+        body: &[u8],
+    ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+        loop {
+            let seq = self.next_seq();
+            let (frame, request_header) = self.encode_request(op, group, id, body, seq)?;
+            let (response_header, response_body) = self.transceive(&frame, &request_header)?;
+
+            check_answer(&request_header, &response_header)?;
+            if self.version_fallback(&request_header, &response_header, &response_body) {
+                continue;
+            }
+
+            return Ok((response_header, response_body));
+        }
+    }
+
+    /// Send a request frame, again if the answer times out, and return the verified response body.
+    pub(crate) fn transceive_retry(
+        &mut self,
+        frame: &[u8],
+        request_header: &NmpHdr,
+    ) -> Result<serde_cbor::Value, Error> {
+        let mut frame = frame.to_vec();
+        let mut request_header = *request_header;
+        let mut nb_retry = self.specs.nb_retry;
+        loop {
+            let (response_header, response_body) = match self.transceive(&frame, &request_header) {
+                Ok(ret) => ret,
+                Err(e) if is_timeout(&e) => {
+                    if nb_retry == 0 {
+                        return Err(e);
+                    }
+                    nb_retry -= 1;
+                    log::debug!("missed answer, nb_retry: {}", nb_retry);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            check_answer(&request_header, &response_header)?;
+            if self.version_fallback(&request_header, &response_header, &response_body) {
+                request_header.version = self.version;
+                frame[..8].copy_from_slice(&request_header.serialize()?);
+                continue;
+            }
+
+            return Ok(response_body);
+        }
+    }
+
+    /// Build a request frame which carries as many data bytes as fit into the MTU.
+    ///
+    /// `encode_body` returns the CBOR body for a chunk of the given length, which starts
+    /// at `max_len` and is reduced until the encoded frame fits. Returns the frame, its
+    /// header and the number of data bytes it carries.
+    pub(crate) fn chunk_frame<I, B>(
+        &self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: I,
+        seq_id: u8,
+        max_len: usize,
+        mut encode_body: B,
+    ) -> Result<(Vec<u8>, NmpHdr, usize), Error>
+    where
+        I: NmpId + Copy,
+        B: FnMut(usize) -> Result<Vec<u8>, Error>,
+    {
+        let mut try_length = max_len;
+        log::debug!("try_length: {}", try_length);
+        loop {
+            let body = encode_body(try_length)?;
+            let (frame, request_header) = self.encode_request(op, group, id, &body, seq_id)?;
+
+            // test if too long
+            let encoded_len = self.transport.encoded_len(frame.len());
+            if encoded_len > self.specs.mtu {
+                let reduce = encoded_len - self.specs.mtu;
+                if reduce > try_length {
+                    bail!("MTU too small");
+                }
+
+                // number of bytes to reduce is base64 encoded, calculate back the number of bytes
+                // and then reduce a bit more for base64 filling and rounding
+                try_length -= reduce * 3 / 4 + 3;
+                log::debug!("new try_length: {}", try_length);
+                continue;
+            }
+
+            return Ok((frame, request_header, try_length));
+        }
+    }
+
+    /// Send a single request, check its result code and decode the response body.
+    pub(crate) fn request_rsp<T: DeserializeOwned>(
+        &mut self,
+        op: NmpOp,
+        group: NmpGroup,
+        id: impl NmpId + Copy,
+        body: &[u8],
+    ) -> Result<T, Error> {
+        let (_, response_body) = self.request(op, group, id, body)?;
+        log::debug!("{:?}", response_body);
+        decode_rsp(response_body)
+    }
+}
+
+/// CBOR encoded empty map, the body of requests without parameters.
+pub(crate) fn empty_body() -> Result<Vec<u8>, Error> {
+    Ok(serde_cbor::to_vec(&std::collections::BTreeMap::<
+        String,
+        String,
+    >::new())?)
+}
+
+/// Fail with the error the device answered with, in either SMP v1 or v2 form.
+pub(crate) fn check_rc(response_body: &serde_cbor::Value) -> Result<(), Error> {
+    match DeviceError::from_body(response_body) {
+        Some(e) => Err(SmpError::Device(e).into()),
+        None => Ok(()),
+    }
+}
+
+/// Decode the answer of the device into `T`, after failing with its error if it has one.
+pub(crate) fn decode_rsp<T: DeserializeOwned>(
+    response_body: serde_cbor::Value,
+) -> Result<T, Error> {
+    check_rc(&response_body)?;
+    serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))
+}
+
+pub(crate) fn check_answer(request_header: &NmpHdr, response_header: &NmpHdr) -> Result<(), Error> {
+    // verify sequence id
+    if response_header.seq != request_header.seq {
+        return Err(SmpError::from(TransportError::WrongSeq {
+            expected: request_header.seq,
+            read: response_header.seq,
+        })
+        .into());
+    }
+
+    let expected_op_type = match request_header.op {
+        NmpOp::Read => NmpOp::ReadRsp,
+        NmpOp::Write => NmpOp::WriteRsp,
+        _ => return Err(SmpError::from(TransportError::WrongAnswer).into()),
+    };
+
+    // verify response
+    if response_header.op != expected_op_type || response_header.group != request_header.group {
+        return Err(SmpError::from(TransportError::WrongAnswer).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_specs;
+    use crate::transfer::encode_frame;
+    use std::collections::{BTreeMap, VecDeque};
+
+    /// Answers like a device with SMP v2 disabled, which refuses v2 requests.
+    struct V1Device {
+        responses: VecDeque<Vec<u8>>,
+    }
+
+    impl SmpTransport for V1Device {
+        fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+            let (header, _) = decode_frame(frame)?;
+            let body = if header.version == SMP_VERSION_2 {
+                serde_cbor::to_vec(&BTreeMap::from([("rc", NmpErr::UnsupportedTooNew as u32)]))?
+            } else {
+                serde_cbor::to_vec(&ImageStateRsp {
+                    images: Vec::new(),
+                    split_status: None,
+                })?
+            };
+            let (frame, _) = encode_frame(
+                NmpOp::ReadRsp,
+                NmpGroup::Image,
+                NmpIdImage::State,
+                &body,
+                header.seq,
+            )?;
+            self.responses.push_back(frame);
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<Vec<u8>, Error> {
+            self.responses
+                .pop_front()
+                .ok_or_else(|| anyhow::format_err!("no answer"))
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn session_runs_several_commands() {
+        let mut session = Session::open(&test_specs()).unwrap();
+        let first = session.list().unwrap();
+        let second = session.list().unwrap();
+        assert_eq!(first.images.len(), 1);
+        assert_eq!(second.images[0].hash, first.images[0].hash);
+        session.erase(None).unwrap();
+    }
+
+    #[test]
+    fn reconnect_closes_the_old_transport() {
+        // the test device can only be open once, like a serial port
+        let mut session = Session::open(&test_specs()).unwrap();
+        assert!(Session::open(&test_specs()).is_err());
+        session.reconnect(Duration::from_secs(3)).unwrap();
+        assert_eq!(session.list().unwrap().images.len(), 1);
+    }
+
+    #[test]
+    fn fall_back_to_smp_v1() {
+        let transport = V1Device {
+            responses: VecDeque::new(),
+        };
+        let mut session = Session::with_transport(&test_specs(), Box::new(transport));
+        assert_eq!(session.smp_version(), SMP_VERSION_2);
+        assert!(session.list().unwrap().images.is_empty());
+        assert_eq!(session.smp_version(), SMP_VERSION_1);
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::info;
+
+use crate::nmp_hdr::*;
+use crate::session::check_rc;
+use crate::session::empty_body;
+use crate::session::Session;
+
+impl Session {
+    /// Read the value of a setting.
+    pub fn settings_read(&mut self, name: &str) -> Result<Vec<u8>, Error> {
+        info!("send settings read request");
+
+        let req = SettingsReadReq {
+            name: name.to_string(),
+            max_size: None,
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        let rsp: SettingsReadRsp =
+            self.request_rsp(NmpOp::Read, NmpGroup::Config, NmpIdConfig::Val, &body)?;
+
+        Ok(rsp.val)
+    }
+
+    /// Write the value of a setting. It is applied with `settings_commit` and
+    /// stored with `settings_save`.
+    pub fn settings_write(&mut self, name: &str, val: &[u8]) -> Result<(), Error> {
+        info!("send settings write request");
+
+        let req = SettingsWriteReq {
+            name: name.to_string(),
+            val: val.to_vec(),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        self.settings_request(NmpOp::Write, NmpIdConfig::Val, &body)
+    }
+
+    /// Delete a setting.
+    pub fn settings_delete(&mut self, name: &str) -> Result<(), Error> {
+        info!("send settings delete request");
+
+        let req = SettingsDeleteReq {
+            name: name.to_string(),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        self.settings_request(NmpOp::Write, NmpIdConfig::Delete, &body)
+    }
+
+    /// Apply the written settings.
+    pub fn settings_commit(&mut self) -> Result<(), Error> {
+        info!("send settings commit request");
+
+        let body = empty_body()?;
+        self.settings_request(NmpOp::Write, NmpIdConfig::Commit, &body)
+    }
+
+    /// Load the settings from persistent storage.
+    pub fn settings_load(&mut self) -> Result<(), Error> {
+        info!("send settings load request");
+
+        let body = empty_body()?;
+        self.settings_request(NmpOp::Read, NmpIdConfig::LoadSave, &body)
+    }
+
+    /// Save the settings to persistent storage.
+    pub fn settings_save(&mut self) -> Result<(), Error> {
+        info!("send settings save request");
+
+        let body = empty_body()?;
+        self.settings_request(NmpOp::Write, NmpIdConfig::LoadSave, &body)
+    }
+
+    fn settings_request(&mut self, op: NmpOp, id: NmpIdConfig, body: &[u8]) -> Result<(), Error> {
+        let (_, response_body) = self.request(op, NmpGroup::Config, id, body)?;
+        check_rc(&response_body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sim::SimOptions;
+    use crate::test_util::{sim_session, test_specs};
+
+    #[test]
+    fn settings_read_write_delete() {
+        let mut session = sim_session(test_specs(), SimOptions::default());
+        session.settings_write("app/name", b"sensor").unwrap();
+        session.settings_write("app/rate", &[0x10, 0]).unwrap();
+        session.settings_commit().unwrap();
+        assert_eq!(session.settings_read("app/name").unwrap(), b"sensor");
+        assert_eq!(session.settings_read("app/rate").unwrap(), [0x10, 0]);
+
+        session.settings_write("app/name", b"probe").unwrap();
+        assert_eq!(session.settings_read("app/name").unwrap(), b"probe");
+
+        session.settings_delete("app/name").unwrap();
+        assert!(session.settings_read("app/name").is_err());
+        assert_eq!(session.settings_read("app/rate").unwrap(), [0x10, 0]);
+
+        // load drops what was not saved
+        session.settings_load().unwrap();
+        assert!(session.settings_read("app/rate").is_err());
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::debug;
+
+use crate::nmp_hdr::*;
+use crate::session::Session;
+
+impl Session {
+    /// Run a shell command on the device and return its output and return code.
+    pub fn shell_exec(&mut self, argv: &[String]) -> Result<ShellExecRsp, Error> {
+        debug!("send shell exec request: {:?}", argv);
+
+        let req = ShellExecReq {
+            argv: argv.to_vec(),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        self.request_rsp(NmpOp::Write, NmpGroup::Shell, NmpIdShell::Exec, &body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sim::SimOptions;
+    use crate::test_util::{sim_session, test_specs};
+
+    #[test]
+    fn shell_exec_return_code() {
+        let mut session = sim_session(test_specs(), SimOptions::default());
+        let argv = |line: &str| line.split(' ').map(str::to_string).collect::<Vec<_>>();
+
+        let rsp = session.shell_exec(&argv("echo hello world")).unwrap();
+        assert_eq!((rsp.o.as_str(), rsp.ret), ("hello world\n", 0));
+        let rsp = session.shell_exec(&argv("reboot now")).unwrap();
+        assert_eq!(
+            (rsp.o.as_str(), rsp.ret),
+            ("reboot: command not found\n", -8)
+        );
+        assert!(session.shell_exec(&[]).is_err());
+    }
+}
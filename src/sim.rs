@@ -0,0 +1,1020 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+// the servers are only used by the simulator program
+#![cfg_attr(not(feature = "sim"), allow(dead_code))]
+
+use anyhow::{Error, Result};
+use log::{debug, info};
+use rand::{thread_rng, Rng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::cmp::min;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Cursor, Read, Write};
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+use crate::crash::CRASH_TYPES;
+use crate::error::{DeviceError, SmpError, TransportError};
+use crate::mcuboot::McubootImage;
+use crate::nmp_hdr::*;
+use crate::transfer::{encode_serial_frame, is_timeout, read_serial_frame, SmpTransport};
+
+/// Settings of the simulated device.
+#[derive(Debug, Clone)]
+pub struct SimOptions {
+    /// Largest request frame the device accepts, without the serial framing
+    pub mtu: usize,
+    /// Line length of the serial framing of the answers
+    pub linelength: usize,
+    /// Size of each flash slot
+    pub slot_size: usize,
+    /// Number of images, each with a primary and a secondary slot
+    pub images: u8,
+    /// Delay before each answer
+    pub latency: Duration,
+    /// Probability that a request gets lost, from 0 to 1
+    pub loss: f64,
+    /// Refuse SMP v2 requests, like a device with v2 disabled
+    pub smp_v1_only: bool,
+}
+
+impl Default for SimOptions {
+    fn default() -> SimOptions {
+        SimOptions {
+            mtu: 1024,
+            linelength: 128,
+            slot_size: 1024 * 1024,
+            images: 1,
+            latency: Duration::ZERO,
+            loss: 0.0,
+            smp_v1_only: false,
+        }
+    }
+}
+
+/// What the device reports about the image in a flash slot.
+struct SlotImage {
+    hash: Vec<u8>,
+    version: String,
+    bootable: bool,
+}
+
+impl SlotImage {
+    fn new(data: &[u8]) -> SlotImage {
+        // like MCUboot, report the hash of the SHA256 TLV
+        match McubootImage::parse(data) {
+            Ok(image) => SlotImage {
+                hash: match image.sha256() {
+                    Some(hash) => hash.to_vec(),
+                    None => Sha256::digest(data).to_vec(),
+                },
+                version: {
+                    // the way Zephyr formats it, with the build number only if it is set
+                    let v = &image.header.version;
+                    match v.build_num {
+                        0 => format!("{}.{}.{}", v.major, v.minor, v.revision),
+                        _ => format!("{}.{}.{}.{}", v.major, v.minor, v.revision, v.build_num),
+                    }
+                },
+                bootable: true,
+            },
+            Err(_) => SlotImage {
+                hash: Sha256::digest(data).to_vec(),
+                version: "0.0.0".to_string(),
+                bootable: false,
+            },
+        }
+    }
+}
+
+/// An upload to the secondary slot, which is not complete yet.
+struct Upload {
+    len: usize,
+    sha: Option<Vec<u8>>,
+    data: Vec<u8>,
+}
+
+/// The two slots of an image, with the MCUboot swap state.
+#[derive(Default)]
+struct ImageSlots {
+    primary: Option<SlotImage>,
+    secondary: Option<SlotImage>,
+    upload: Option<Upload>,
+    /// Swap to the secondary image on the next reset
+    pending: bool,
+    /// Keep the secondary image without confirmation
+    permanent: bool,
+    /// The primary image was confirmed and won't be reverted
+    confirmed: bool,
+}
+
+/// A simulated device, which answers SMP requests like a Zephyr application with MCUboot.
+///
+/// The flash slots, files and settings are kept in memory. It can be used as transport
+/// within a process, or serve a PTY or UDP socket for the command line program.
+pub struct SimDevice {
+    options: SimOptions,
+    images: Vec<ImageSlots>,
+    files: BTreeMap<String, Vec<u8>>,
+    settings: BTreeMap<String, Vec<u8>>,
+    saved_settings: BTreeMap<String, Vec<u8>>,
+    datetime: String,
+    log: Vec<LogEntry>,
+    core: Option<Vec<u8>>,
+    reset_pending: bool,
+    crashed: bool,
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl SimDevice {
+    pub fn new(options: SimOptions) -> SimDevice {
+        let images = (0..options.images).map(|_| ImageSlots::default()).collect();
+        SimDevice {
+            options,
+            images,
+            files: BTreeMap::new(),
+            settings: BTreeMap::new(),
+            saved_settings: BTreeMap::new(),
+            datetime: "1970-01-01T00:00:00".to_string(),
+            log: Vec::new(),
+            core: None,
+            reset_pending: false,
+            crashed: false,
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Put a confirmed image into the primary slot, as if it was flashed by a programmer.
+    pub fn load(&mut self, image: u8, data: &[u8]) -> Result<(), Error> {
+        let Some(slots) = self.images.get_mut(image as usize) else {
+            anyhow::bail!("the device has no image {}", image);
+        };
+        slots.primary = Some(SlotImage::new(data));
+        slots.confirmed = true;
+        Ok(())
+    }
+
+    /// Handle a request frame and return the answer frame, none if the request gets no answer.
+    pub fn handle(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let mut cursor = Cursor::new(frame);
+        let header = match NmpHdr::deserialize(&mut cursor) {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("dropping request: {}", e);
+                return None;
+            }
+        };
+        let op = match header.op {
+            NmpOp::Read => NmpOp::ReadRsp,
+            NmpOp::Write => NmpOp::WriteRsp,
+            _ => return None,
+        };
+        let version = if self.options.smp_v1_only {
+            SMP_VERSION_1
+        } else {
+            header.version
+        };
+        debug!("request: {:?}", header);
+
+        let result = if frame.len() > self.options.mtu {
+            Err(DeviceError::Mgmt(NmpErr::EMsgSize))
+        } else if version != header.version {
+            Err(DeviceError::Mgmt(NmpErr::UnsupportedTooNew))
+        } else {
+            let body = &frame[8..];
+            let write = header.op == NmpOp::Write;
+            match header.group {
+                NmpGroup::Default => self.os(header.id, write, body),
+                NmpGroup::Image => self.image(header.id, write, body),
+                NmpGroup::Config => self.settings(header.id, write, body),
+                NmpGroup::Fs => self.fs(header.id, write, body),
+                NmpGroup::Log => self.logs(header.id, write, body),
+                NmpGroup::Crash => self.crash(header.id, body),
+                NmpGroup::Run => self.run(header.id, write, body),
+                NmpGroup::Shell => self.shell(header.id, body),
+                _ => Err(DeviceError::Mgmt(NmpErr::ENotSup)),
+            }
+        };
+        // a crash resets the device before it can answer
+        if self.crashed {
+            self.crashed = false;
+            self.reset();
+            return None;
+        }
+        let body = match result {
+            Ok(body) => body,
+            Err(e) => {
+                debug!("answering with {}", e);
+                error_body(version, e)
+            }
+        };
+
+        let body = serde_cbor::to_vec(&body).ok()?;
+        let response_header = NmpHdr {
+            version,
+            op,
+            len: body.len() as u16,
+            ..header
+        };
+        let mut response = response_header.serialize().ok()?;
+        response.extend(body);
+
+        // the answer to a reset request is sent before the reset
+        if self.reset_pending {
+            self.reset();
+        }
+        Some(response)
+    }
+
+    /// Handle a request frame with the configured loss and latency.
+    pub fn answer(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        if self.options.loss > 0.0 && thread_rng().gen_bool(self.options.loss.min(1.0)) {
+            debug!("losing request");
+            return None;
+        }
+        thread::sleep(self.options.latency);
+        self.handle(frame)
+    }
+
+    /// Answer requests on a UDP socket, one frame per datagram, until the socket fails.
+    pub fn serve_udp(&mut self, socket: &UdpSocket) -> Result<(), Error> {
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf)?;
+            if let Some(response) = self.answer(&buf[..len]) {
+                socket.send_to(&response, peer)?;
+            }
+        }
+    }
+
+    /// Answer requests in the base64 console framing, e.g. on the master side of a PTY,
+    /// until the port fails.
+    pub fn serve_serial<P: Read + Write + ?Sized>(&mut self, port: &mut P) -> Result<(), Error> {
+        loop {
+            let frame = match read_serial_frame(&mut *port) {
+                Ok(frame) => frame,
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) if e.downcast_ref::<std::io::Error>().is_some() => return Err(e),
+                Err(e) => {
+                    debug!("dropping request: {}", e);
+                    continue;
+                }
+            };
+            if let Some(response) = self.answer(&frame) {
+                port.write_all(&encode_serial_frame(self.options.linelength, &response)?)?;
+                port.flush()?;
+            }
+        }
+    }
+
+    /// Restart like MCUboot: swap in a pending image, or revert an unconfirmed one.
+    fn reset(&mut self) {
+        info!("reset");
+        self.reset_pending = false;
+        for slots in &mut self.images {
+            if slots.pending {
+                std::mem::swap(&mut slots.primary, &mut slots.secondary);
+                slots.confirmed = slots.permanent;
+            } else if slots.primary.is_some() && !slots.confirmed {
+                std::mem::swap(&mut slots.primary, &mut slots.secondary);
+                slots.confirmed = true;
+            }
+            slots.pending = false;
+            slots.permanent = false;
+            slots.upload = None;
+        }
+        self.settings = self.saved_settings.clone();
+    }
+
+    fn os(&mut self, id: u8, write: bool, body: &[u8]) -> Result<Value, DeviceError> {
+        match id {
+            id if id == NmpIdDef::Echo as u8 => {
+                let req: EchoReq = parse(body)?;
+                reply(&EchoRsp { r: req.d })
+            }
+            id if id == NmpIdDef::ConsEchoCtrl as u8 => ok(),
+            id if id == NmpIdDef::TaskStat as u8 => {
+                let tasks = BTreeMap::from([
+                    (
+                        "idle".to_string(),
+                        TaskStatEntry {
+                            prio: 15,
+                            tid: 1,
+                            stksiz: 320,
+                            stkuse: 64,
+                            ..Default::default()
+                        },
+                    ),
+                    (
+                        "main".to_string(),
+                        TaskStatEntry {
+                            prio: 0,
+                            tid: 2,
+                            stksiz: 2048,
+                            stkuse: 512,
+                            ..Default::default()
+                        },
+                    ),
+                ]);
+                reply(&TaskStatRsp { tasks })
+            }
+            id if id == NmpIdDef::MpStat as u8 => {
+                let mpools = BTreeMap::from([(
+                    "smp".to_string(),
+                    MpStatEntry {
+                        blksiz: self.options.mtu as u32,
+                        nblks: 4,
+                        nfree: 4,
+                        min: 3,
+                    },
+                )]);
+                reply(&MpStatRsp { mpools })
+            }
+            id if id == NmpIdDef::DateTimeStr as u8 => {
+                if write {
+                    let req: DateTimeReq = parse(body)?;
+                    self.datetime = req.datetime;
+                    ok()
+                } else {
+                    reply(&DateTimeRsp {
+                        datetime: self.datetime.clone(),
+                    })
+                }
+            }
+            id if id == NmpIdDef::Reset as u8 => {
+                self.reset_pending = true;
+                ok()
+            }
+            id if id == NmpIdDef::McumgrParams as u8 => reply(&McumgrParamsRsp {
+                buf_size: self.options.mtu as u32,
+                buf_count: 4,
+            }),
+            id if id == NmpIdDef::Info as u8 => {
+                let req: OsInfoReq = parse(body)?;
+                reply(&OsInfoRsp {
+                    output: os_info(req.format.as_deref().unwrap_or("s"))?,
+                })
+            }
+            _ => Err(DeviceError::Mgmt(NmpErr::ENotSup)),
+        }
+    }
+
+    fn image(&mut self, id: u8, write: bool, body: &[u8]) -> Result<Value, DeviceError> {
+        match id {
+            id if id == NmpIdImage::State as u8 => {
+                if write {
+                    let req: ImageStateReq = parse(body)?;
+                    self.image_state_write(req)?;
+                }
+                reply(&ImageStateRsp {
+                    images: self.image_state(),
+                    split_status: None,
+                })
+            }
+            id if id == NmpIdImage::Upload as u8 => {
+                let req: ImageUploadReq = parse(body)?;
+                self.image_upload(req)
+            }
+            id if id == NmpIdImage::Erase as u8 => {
+                let req: ImageEraseReq = parse(body)?;
+                self.image_erase(req.slot.unwrap_or(1))
+            }
+            id if id == NmpIdImage::CoreList as u8 => match self.core {
+                Some(_) => ok(),
+                None => Err(DeviceError::Mgmt(NmpErr::ENoEnt)),
+            },
+            id if id == NmpIdImage::CoreLoad as u8 && write => {
+                self.core = None;
+                ok()
+            }
+            id if id == NmpIdImage::CoreLoad as u8 => {
+                let req: CoreLoadReq = parse(body)?;
+                let Some(core) = &self.core else {
+                    return Err(DeviceError::Mgmt(NmpErr::ENoEnt));
+                };
+                let off = req.off as usize;
+                if off > core.len() {
+                    return Err(DeviceError::Mgmt(NmpErr::EInvalid));
+                }
+                let end = min(core.len(), off + self.options.mtu / 2);
+                reply(&CoreLoadRsp {
+                    off: req.off,
+                    data: core[off..end].to_vec(),
+                    len: (off == 0).then_some(core.len() as u32),
+                })
+            }
+            _ => Err(DeviceError::Mgmt(NmpErr::ENotSup)),
+        }
+    }
+
+    fn image_state(&self) -> Vec<ImageStateEntry> {
+        let mut entries = Vec::new();
+        for (image, slots) in self.images.iter().enumerate() {
+            if let Some(primary) = &slots.primary {
+                entries.push(ImageStateEntry {
+                    image: image as u32,
+                    slot: 0,
+                    version: primary.version.clone(),
+                    hash: primary.hash.clone(),
+                    bootable: primary.bootable,
+                    pending: false,
+                    confirmed: slots.confirmed,
+                    active: true,
+                    permanent: false,
+                });
+            }
+            if let Some(secondary) = &slots.secondary {
+                entries.push(ImageStateEntry {
+                    image: image as u32,
+                    slot: 1,
+                    version: secondary.version.clone(),
+                    hash: secondary.hash.clone(),
+                    bootable: secondary.bootable,
+                    pending: slots.pending,
+                    confirmed: false,
+                    active: false,
+                    permanent: slots.permanent,
+                });
+            }
+        }
+        entries
+    }
+
+    fn image_state_write(&mut self, req: ImageStateReq) -> Result<(), DeviceError> {
+        let confirm = req.confirm.unwrap_or(false);
+
+        // confirming without hash confirms the running images
+        if req.hash.is_empty() {
+            if !confirm {
+                return Err(DeviceError::Mgmt(NmpErr::EInvalid));
+            }
+            for slots in &mut self.images {
+                slots.confirmed = true;
+            }
+            return Ok(());
+        }
+
+        for slots in &mut self.images {
+            if slots.primary.as_ref().is_some_and(|p| p.hash == req.hash) {
+                if !confirm {
+                    return Err(DeviceError::Image(
+                        NmpImageErr::ImageSettingTestToActiveDenied,
+                    ));
+                }
+                slots.confirmed = true;
+                return Ok(());
+            }
+            if slots.secondary.as_ref().is_some_and(|s| s.hash == req.hash) {
+                if !slots.secondary.as_ref().is_some_and(|s| s.bootable) {
+                    return Err(DeviceError::Image(NmpImageErr::InvalidImageHeaderMagic));
+                }
+                slots.pending = true;
+                slots.permanent = confirm;
+                return Ok(());
+            }
+        }
+        Err(DeviceError::Image(NmpImageErr::HashNotFound))
+    }
+
+    fn image_upload(&mut self, req: ImageUploadReq) -> Result<Value, DeviceError> {
+        let slot_size = self.options.slot_size;
+        let Some(slots) = self.images.get_mut(req.image_num as usize) else {
+            return Err(DeviceError::Image(NmpImageErr::InvalidSlot));
+        };
+
+        if req.off == 0 {
+            let Some(len) = req.len else {
+                return Err(DeviceError::Mgmt(NmpErr::EInvalid));
+            };
+            let len = len as usize;
+
+            // the same image again continues where the last upload stopped
+            let resume = req.data_sha.is_some()
+                && slots
+                    .upload
+                    .as_ref()
+                    .is_some_and(|upload| upload.len == len && upload.sha == req.data_sha);
+            if !resume {
+                if len > slot_size {
+                    return Err(DeviceError::Image(NmpImageErr::InvalidImageTooLarge));
+                }
+                if slots.pending {
+                    return Err(DeviceError::Image(NmpImageErr::ImageAlreadyPending));
+                }
+                debug!("erasing the secondary slot of image {}", req.image_num);
+                slots.secondary = None;
+                slots.upload = Some(Upload {
+                    len,
+                    sha: req.data_sha,
+                    data: Vec::with_capacity(len),
+                });
+            }
+        }
+
+        let Some(upload) = slots.upload.as_mut() else {
+            return Err(DeviceError::Mgmt(NmpErr::EInvalid));
+        };
+
+        // a chunk at another offset is ignored, the answer tells where to continue
+        let off = req.off as usize;
+        if off == upload.data.len() {
+            if off + req.data.len() > upload.len {
+                return Err(DeviceError::Image(NmpImageErr::InvalidImageDataOverrun));
+            }
+            upload.data.extend_from_slice(&req.data);
+        }
+        let off = upload.data.len();
+        if off == upload.len {
+            if let Some(upload) = slots.upload.take() {
+                slots.secondary = Some(SlotImage::new(&upload.data));
+            }
+        }
+
+        reply(&BTreeMap::from([("off", off)]))
+    }
+
+    fn image_erase(&mut self, slot: u32) -> Result<Value, DeviceError> {
+        // image n has the slots 2n and 2n + 1
+        let Some(slots) = self.images.get_mut(slot as usize / 2) else {
+            return Err(DeviceError::Image(NmpImageErr::InvalidSlot));
+        };
+        if slot.is_multiple_of(2) || slots.pending {
+            return Err(DeviceError::Mgmt(NmpErr::EBadState));
+        }
+        slots.secondary = None;
+        slots.upload = None;
+        ok()
+    }
+
+    fn settings(&mut self, id: u8, write: bool, body: &[u8]) -> Result<Value, DeviceError> {
+        match id {
+            id if id == NmpIdConfig::Val as u8 => {
+                if write {
+                    let req: SettingsWriteReq = parse(body)?;
+                    self.settings.insert(req.name, req.val);
+                    ok()
+                } else {
+                    let req: SettingsReadReq = parse(body)?;
+                    let Some(val) = self.settings.get(&req.name) else {
+                        return Err(DeviceError::Mgmt(NmpErr::ENoEnt));
+                    };
+                    reply(&SettingsReadRsp {
+                        val: val.clone(),
+                        max_size: None,
+                    })
+                }
+            }
+            id if id == NmpIdConfig::Delete as u8 => {
+                let req: SettingsDeleteReq = parse(body)?;
+                self.settings.remove(&req.name);
+                ok()
+            }
+            id if id == NmpIdConfig::Commit as u8 => ok(),
+            id if id == NmpIdConfig::LoadSave as u8 => {
+                if write {
+                    self.saved_settings = self.settings.clone();
+                } else {
+                    self.settings = self.saved_settings.clone();
+                }
+                ok()
+            }
+            _ => Err(DeviceError::Mgmt(NmpErr::ENotSup)),
+        }
+    }
+
+    fn fs(&mut self, id: u8, write: bool, body: &[u8]) -> Result<Value, DeviceError> {
+        match id {
+            id if id == NmpIdFs::File as u8 && write => {
+                let req: FsUploadReq = parse(body)?;
+                if req.name.is_empty() {
+                    return Err(DeviceError::Fs(NmpFsErr::FileInvalidName));
+                }
+                if req.off == 0 {
+                    self.files.insert(req.name.clone(), Vec::new());
+                }
+                let Some(file) = self.files.get_mut(&req.name) else {
+                    return Err(DeviceError::Fs(NmpFsErr::FileNotFound));
+                };
+                if req.off == file.len() as u64 {
+                    file.extend_from_slice(&req.data);
+                }
+                reply(&FsUploadRsp {
+                    off: file.len() as u64,
+                })
+            }
+            id if id == NmpIdFs::File as u8 => {
+                let req: FsDownloadReq = parse(body)?;
+                let file = self.file(&req.name)?;
+                let off = req.off as usize;
+                if off > file.len() {
+                    return Err(DeviceError::Fs(NmpFsErr::FileOffsetLargerThanFile));
+                }
+                let end = min(file.len(), off + self.options.mtu / 2);
+                reply(&FsDownloadRsp {
+                    off: req.off,
+                    data: file[off..end].to_vec(),
+                    len: (off == 0).then_some(file.len() as u64),
+                })
+            }
+            id if id == NmpIdFs::Status as u8 => {
+                let req: FsStatusReq = parse(body)?;
+                let len = self.file(&req.name)?.len() as u64;
+                reply(&FsStatusRsp { len })
+            }
+            id if id == NmpIdFs::HashChecksum as u8 => {
+                let req: FsHashReq = parse(body)?;
+                let file = self.file(&req.name)?;
+                let off = req.off.unwrap_or(0) as usize;
+                if off > file.len() {
+                    return Err(DeviceError::Fs(NmpFsErr::FileOffsetLargerThanFile));
+                }
+                let end = match req.len {
+                    Some(len) => min(file.len(), off + len as usize),
+                    None => file.len(),
+                };
+                let data = &file[off..end];
+                let hash_type = req.hash_type.unwrap_or_else(|| "crc32".to_string());
+                let output = match hash_type.as_str() {
+                    "crc32" => Value::Integer(crc32(data) as i128),
+                    "sha256" => Value::Bytes(Sha256::digest(data).to_vec()),
+                    _ => return Err(DeviceError::Fs(NmpFsErr::ChecksumHashNotFound)),
+                };
+                reply(&FsHashRsp {
+                    hash_type,
+                    off: off as u64,
+                    len: data.len() as u64,
+                    output,
+                })
+            }
+            id if id == NmpIdFs::Close as u8 => ok(),
+            _ => Err(DeviceError::Mgmt(NmpErr::ENotSup)),
+        }
+    }
+
+    fn logs(&mut self, id: u8, write: bool, body: &[u8]) -> Result<Value, DeviceError> {
+        match id {
+            id if id == NmpIdLog::Show as u8 => {
+                let req: LogShowReq = parse(body)?;
+                let index = req.index.unwrap_or(0);
+                let entries = self
+                    .log
+                    .iter()
+                    .filter(|entry| entry.index >= index)
+                    .cloned()
+                    .collect();
+                reply(&LogShowRsp {
+                    next_index: self.log.last().map_or(index, |entry| entry.index + 1),
+                    logs: vec![LogShowLog {
+                        name: "log".to_string(),
+                        log_type: 0,
+                        entries,
+                    }],
+                })
+            }
+            id if id == NmpIdLog::Clear as u8 && write => {
+                self.log.clear();
+                ok()
+            }
+            id if id == NmpIdLog::List as u8 => reply(&LogListRsp {
+                log_list: vec!["log".to_string()],
+            }),
+            _ => Err(DeviceError::Mgmt(NmpErr::ENotSup)),
+        }
+    }
+
+    /// Add a message to the log, the indexes continue after a clear.
+    fn log(&mut self, msg: String) {
+        let index = self.log.last().map_or(0, |entry| entry.index + 1);
+        self.log.push(LogEntry {
+            msg: Value::Text(msg),
+            ts: 0,
+            level: 1,
+            index,
+            module: 0,
+            entry_type: None,
+        });
+    }
+
+    fn run(&mut self, id: u8, write: bool, body: &[u8]) -> Result<Value, DeviceError> {
+        match id {
+            id if id == NmpIdRun::Test as u8 && write => {
+                let req: RunTestReq = parse(body)?;
+                let suites: Vec<_> = TEST_SUITES
+                    .iter()
+                    .filter(|(suite, _)| req.testname == "all" || req.testname == *suite)
+                    .collect();
+                if suites.is_empty() {
+                    return Err(DeviceError::Mgmt(NmpErr::ENoEnt));
+                }
+                let token = req
+                    .token
+                    .map(|token| format!(" {}", token))
+                    .unwrap_or_default();
+                for (suite, tests) in suites {
+                    self.log(format!("running {}{}", suite, token));
+                    for (test, passed) in tests.iter() {
+                        let outcome = if *passed { "[pass]" } else { "[fail]" };
+                        self.log(format!("{} {}.{}{}", outcome, suite, test, token));
+                    }
+                }
+                self.log(format!("[done]{}", token));
+                ok()
+            }
+            id if id == NmpIdRun::List as u8 => reply(&RunListRsp {
+                run_list: TEST_SUITES
+                    .iter()
+                    .map(|(suite, _)| suite.to_string())
+                    .collect(),
+            }),
+            _ => Err(DeviceError::Mgmt(NmpErr::ENotSup)),
+        }
+    }
+
+    fn shell(&mut self, id: u8, body: &[u8]) -> Result<Value, DeviceError> {
+        if id != NmpIdShell::Exec as u8 {
+            return Err(DeviceError::Mgmt(NmpErr::ENotSup));
+        }
+        let req: ShellExecReq = parse(body)?;
+        let argv: Vec<&str> = req.argv.iter().map(String::as_str).collect();
+
+        // a few commands of the Zephyr shell, unknown ones fail with -ENOEXEC
+        let (o, ret) = match argv[..] {
+            [] => return Err(DeviceError::Mgmt(NmpErr::EInvalid)),
+            ["echo", ref args @ ..] => (format!("{}\n", args.join(" ")), 0),
+            ["kernel", "version"] => ("Zephyr version 3.7.0\n".to_string(), 0),
+            [command, ..] => (format!("{}: command not found\n", command), -8),
+        };
+        reply(&ShellExecRsp { o, ret })
+    }
+
+    fn crash(&mut self, id: u8, body: &[u8]) -> Result<Value, DeviceError> {
+        if id != NmpIdCrash::Trigger as u8 {
+            return Err(DeviceError::Mgmt(NmpErr::ENotSup));
+        }
+        let req: CrashTriggerReq = parse(body)?;
+        if !CRASH_TYPES.contains(&req.t.as_str()) {
+            return Err(DeviceError::Mgmt(NmpErr::EInvalid));
+        }
+        self.crashed = true;
+        self.core = Some(core_dump());
+        ok()
+    }
+
+    fn file(&self, name: &str) -> Result<&Vec<u8>, DeviceError> {
+        self.files
+            .get(name)
+            .ok_or(DeviceError::Fs(NmpFsErr::FileNotFound))
+    }
+}
+
+impl SmpTransport for SimDevice {
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+        if let Some(response) = self.answer(frame) {
+            self.responses.push_back(response);
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        match self.responses.pop_front() {
+            Some(frame) => Ok(frame),
+            None => Err(SmpError::from(TransportError::Timeout).into()),
+        }
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, DeviceError> {
+    serde_cbor::from_slice(body).map_err(|_| DeviceError::Mgmt(NmpErr::EInvalid))
+}
+
+fn reply<T: Serialize>(rsp: &T) -> Result<Value, DeviceError> {
+    serde_cbor::value::to_value(rsp).map_err(|_| DeviceError::Mgmt(NmpErr::EUnknown))
+}
+
+fn ok() -> Result<Value, DeviceError> {
+    Ok(Value::Map(BTreeMap::new()))
+}
+
+/// Error answer in the form of the SMP version: generic codes are a top level `rc`,
+/// group codes are `err: {group, rc}` in v2, and unknown in v1.
+fn error_body(version: u8, e: DeviceError) -> Value {
+    let text = |s: &str| Value::Text(s.to_string());
+    let body = match e.group() {
+        Some(group) if version != SMP_VERSION_1 => BTreeMap::from([(
+            text("err"),
+            Value::Map(BTreeMap::from([
+                (text("group"), Value::Integer(group as i128)),
+                (text("rc"), Value::Integer(e.rc() as i128)),
+            ])),
+        )]),
+        Some(_) => BTreeMap::from([(text("rc"), Value::Integer(NmpErr::EUnknown as i128))]),
+        None => BTreeMap::from([(text("rc"), Value::Integer(e.rc() as i128))]),
+    };
+    Value::Map(body)
+}
+
+/// The core dump a crash leaves behind.
+pub(crate) fn core_dump() -> Vec<u8> {
+    (0..3000u32).map(|i| (i * 7) as u8).collect()
+}
+
+/// Test suites of the run group, each with the outcome of its tests.
+const TEST_SUITES: [(&str, &[(&str, bool)]); 2] = [
+    ("kernel", &[("threads", true), ("timers", true)]),
+    ("flash", &[("erase", true), ("write", false)]),
+];
+
+/// Output of the OS info request, with the fields of `uname`.
+fn os_info(format: &str) -> Result<String, DeviceError> {
+    let format = if format.contains('a') {
+        "snrvbmpio"
+    } else {
+        format
+    };
+    let mut fields = Vec::new();
+    for c in format.chars() {
+        fields.push(match c {
+            's' => "Zephyr",
+            'n' => "mcumgr-sim",
+            'r' => "3.7.0",
+            'v' => "v3.7.0",
+            'b' => "mcumgr-sim",
+            'm' => "sim",
+            'p' => "sim",
+            'i' => "native_sim",
+            'o' => "Zephyr",
+            _ => return Err(DeviceError::Mgmt(NmpErr::EInvalid)),
+        });
+    }
+    Ok(fields.join(" "))
+}
+
+/// CRC32 like the fs group of Zephyr calculates it, the IEEE polynomial.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::UploadOptions;
+    use crate::mcuboot::tests::test_image;
+    use crate::session::Session;
+    use crate::test_util::{sim_session, test_specs};
+    use crate::transfer::SerialSpecs;
+
+    fn session(options: SimOptions) -> Session {
+        let specs = SerialSpecs {
+            window: 4,
+            ..test_specs()
+        };
+        sim_session(specs, options)
+    }
+
+    fn upload(session: &mut Session, image: &[u8]) {
+        session
+            .upload_image(
+                image,
+                0,
+                &UploadOptions::default(),
+                &mut None::<fn(u64, u64)>,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_swap_and_revert() {
+        let hash = Sha256::digest(b"new firmware").to_vec();
+        let image = test_image(&[0x5a; 3000], &hash);
+        let mut session = session(SimOptions::default());
+
+        upload(&mut session, &image);
+        let state = session.list().unwrap();
+        assert_eq!(state.images.len(), 1);
+        assert_eq!((state.images[0].slot, &state.images[0].hash), (1, &hash));
+
+        // tested, it runs until the next reset
+        session.test(hash.clone(), None).unwrap();
+        session.reset().unwrap();
+        let state = session.list().unwrap();
+        assert!(state.images[0].active && !state.images[0].confirmed);
+        assert_eq!(state.images[0].version, "1.2.3.4");
+
+        session.reset().unwrap();
+        let state = session.list().unwrap();
+        assert_eq!(state.images[0].slot, 1);
+
+        // confirmed, it stays
+        session.test(hash.clone(), None).unwrap();
+        session.reset().unwrap();
+        session.test(Vec::new(), Some(true)).unwrap();
+        session.reset().unwrap();
+        let state = session.list().unwrap();
+        assert_eq!(state.images[0].hash, hash);
+        assert!(state.images[0].active && state.images[0].confirmed);
+    }
+
+    /// Run `f` until it succeeds, requests without answer fail.
+    fn retry<T>(mut f: impl FnMut() -> Result<T, Error>) -> T {
+        for _ in 0..20 {
+            if let Ok(ret) = f() {
+                return ret;
+            }
+        }
+        panic!("no success after 20 tries");
+    }
+
+    #[test]
+    fn upload_with_loss_and_v1() {
+        let hash = Sha256::digest(b"lossy").to_vec();
+        let image = test_image(&[0xa5; 5000], &hash);
+        let mut session = session(SimOptions {
+            loss: 0.1,
+            smp_v1_only: true,
+            ..Default::default()
+        });
+
+        assert_eq!(retry(|| session.echo("hello")), "hello");
+        assert_eq!(session.smp_version(), SMP_VERSION_1);
+
+        // the windowed upload resends lost chunks, and continues after a failure
+        retry(|| {
+            session.upload_image(
+                &image,
+                0,
+                &UploadOptions::default(),
+                &mut None::<fn(u64, u64)>,
+            )
+        });
+        let state = retry(|| session.list());
+        assert_eq!(state.images[0].hash, hash);
+    }
+
+    #[test]
+    fn block_upload_to_v1_device() {
+        let hash = Sha256::digest(b"blocks").to_vec();
+        let image = test_image(&[0x11; 2000], &hash);
+        let specs = SerialSpecs {
+            window: 1,
+            ..test_specs()
+        };
+        let device = SimDevice::new(SimOptions {
+            smp_v1_only: true,
+            ..Default::default()
+        });
+        let mut session = Session::with_transport(&specs, Box::new(device));
+        upload(&mut session, &image);
+        assert_eq!(session.smp_version(), SMP_VERSION_1);
+        assert_eq!(session.list().unwrap().images[0].hash, hash);
+    }
+
+    #[test]
+    fn fs_and_settings_errors() {
+        let mut session = session(SimOptions::default());
+        let e = session.fs_stat("/lfs/missing").unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<SmpError>(),
+            Some(&SmpError::Device(DeviceError::Fs(NmpFsErr::FileNotFound)))
+        );
+        let e = session.settings_read("missing").unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<SmpError>(),
+            Some(&SmpError::Device(DeviceError::Mgmt(NmpErr::ENoEnt)))
+        );
+    }
+
+    #[test]
+    fn settings_survive_reset_when_saved() {
+        let mut session = session(SimOptions::default());
+        session.settings_write("app/a", b"1").unwrap();
+        session.settings_save().unwrap();
+        session.settings_write("app/b", b"2").unwrap();
+        session.reset().unwrap();
+        assert_eq!(session.settings_read("app/a").unwrap(), b"1");
+        assert!(session.settings_read("app/b").is_err());
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::{debug, info};
+use std::collections::BTreeMap;
+
+use crate::nmp_hdr::*;
+use crate::session::empty_body;
+use crate::session::Session;
+
+impl Session {
+    /// List the names of the statistics groups of the device.
+    pub fn stat_list(&mut self) -> Result<Vec<String>, Error> {
+        info!("send statistics list request");
+
+        let body = empty_body()?;
+        let rsp: StatListRsp =
+            self.request_rsp(NmpOp::Read, NmpGroup::Stat, NmpIdStat::List, &body)?;
+
+        Ok(rsp.stat_list)
+    }
+
+    /// Read all counters of a statistics group.
+    pub fn stat_read(&mut self, name: &str) -> Result<StatReadRsp, Error> {
+        debug!("send statistics read request");
+
+        let req = StatReadReq {
+            name: name.to_string(),
+        };
+        let body = serde_cbor::to_vec(&req)?;
+        self.request_rsp(NmpOp::Read, NmpGroup::Stat, NmpIdStat::Read, &body)
+    }
+}
+
+impl StatReadRsp {
+    /// Change of each counter since an earlier read of the same group.
+    /// Counters which were not in the earlier read count from 0.
+    pub fn deltas(&self, previous: &StatReadRsp) -> BTreeMap<String, i128> {
+        self.fields
+            .iter()
+            .map(|(field, value)| {
+                let before = previous.fields.get(field).copied().unwrap_or(0);
+                (field.clone(), *value as i128 - before as i128)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat_deltas() {
+        let previous = StatReadRsp {
+            name: "ble_ll".to_string(),
+            fields: BTreeMap::from([("rx".to_string(), 10), ("tx".to_string(), 7)]),
+        };
+        let current = StatReadRsp {
+            name: "ble_ll".to_string(),
+            fields: BTreeMap::from([
+                ("rx".to_string(), 15),
+                ("tx".to_string(), 7),
+                ("crc_err".to_string(), 2),
+            ]),
+        };
+        let deltas = current.deltas(&previous);
+        assert_eq!(deltas["rx"], 5);
+        assert_eq!(deltas["tx"], 0);
+        assert_eq!(deltas["crc_err"], 2);
+    }
+}
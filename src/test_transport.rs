@@ -0,0 +1,162 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, Error, Result};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{SmpError, TransportError};
+use crate::nmp_hdr::*;
+use crate::transfer::encode_frame;
+use crate::transfer::SmpTransport;
+
+pub struct TestTransport {
+    responses: VecDeque<Vec<u8>>,
+    total_len: u32,
+    images: Vec<ImageStateEntry>,
+}
+
+thread_local! {
+    static OPEN: Cell<bool> = const { Cell::new(false) };
+}
+
+impl TestTransport {
+    /// Open the test device, which like a serial port can only be open once at a time.
+    pub fn open() -> Result<TestTransport, Error> {
+        if OPEN.with(|open| open.replace(true)) {
+            bail!("test device is busy");
+        }
+        Ok(TestTransport {
+            responses: VecDeque::new(),
+            total_len: 0,
+            images: vec![ImageStateEntry {
+                image: 1,
+                slot: 0,
+                version: "1.0.0".to_string(),
+                hash: hex::decode(
+                    "61ddbce8f52e53715f57b360a5af0700ba17122114c94a11b86d9097f7e09cc3",
+                )
+                .unwrap(),
+                bootable: false,
+                pending: false,
+                confirmed: false,
+                active: true,
+                permanent: false,
+            }],
+        })
+    }
+}
+
+impl Drop for TestTransport {
+    fn drop(&mut self) {
+        OPEN.with(|open| open.set(false));
+    }
+}
+
+impl SmpTransport for TestTransport {
+    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut request_cursor = Cursor::new(data);
+        let request_header = NmpHdr::deserialize(&mut request_cursor)?;
+        // let header_len: usize = 8;
+        // let request_body = data[header_len..].to_vec();
+
+        match request_header.id {
+            // only the image group is simulated, other requests get no answer
+            _ if request_header.group != NmpGroup::Image => {}
+            id if id == NmpIdImage::State as u8 => {
+                if request_header.op == NmpOp::Read {
+                    let state_response = ImageStateRsp {
+                        images: self.images.clone(),
+                        split_status: None,
+                    };
+                    let body = serde_cbor::to_vec(&state_response).unwrap();
+                    let (encoded_response, _) = encode_frame(
+                        NmpOp::ReadRsp,
+                        NmpGroup::Image,
+                        NmpIdImage::State,
+                        &body,
+                        request_header.seq,
+                    )
+                    .unwrap();
+                    self.responses.push_back(encoded_response);
+                } else if request_header.op == NmpOp::Write {
+                    // let request: ImageStateReq = serde_cbor::from_slice(request_body.as_slice()).unwrap();
+                    let body = serde_cbor::to_vec(&serde_cbor::Value::Null).unwrap();
+                    let (encoded_response, _) = encode_frame(
+                        NmpOp::WriteRsp,
+                        NmpGroup::Image,
+                        NmpIdImage::Erase,
+                        &body,
+                        request_header.seq,
+                    )
+                    .unwrap();
+                    self.responses.push_back(encoded_response);
+                }
+            }
+            id if id == NmpIdImage::Upload as u8 => {
+                let body_start = request_cursor.position() as usize;
+                let body_end = data.len();
+                let body = &data[body_start..body_end];
+
+                let image_upload_req: ImageUploadReq = serde_cbor::from_slice(body).unwrap();
+                if image_upload_req.off == 0 {
+                    self.total_len = image_upload_req.len.unwrap();
+                }
+                let mut off_value = image_upload_req.off + image_upload_req.data.len() as u32;
+                if off_value > self.total_len {
+                    off_value = self.total_len;
+                }
+
+                let mut response_map = std::collections::BTreeMap::new();
+                response_map.insert("rc", 0);
+                response_map.insert("off", off_value);
+
+                let cbor_body = serde_cbor::to_vec(&response_map).unwrap();
+                let (encoded_response, _) = encode_frame(
+                    NmpOp::WriteRsp,
+                    NmpGroup::Image,
+                    NmpIdImage::State,
+                    &cbor_body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.responses.push_back(encoded_response);
+            }
+            id if id == NmpIdImage::Erase as u8 => {
+                // let request: ImageEraseReq = serde_cbor::from_slice(request_body.as_slice()).unwrap();
+                let body = serde_cbor::to_vec(&serde_cbor::Value::Null).unwrap();
+                let (encoded_response, _) = encode_frame(
+                    NmpOp::WriteRsp,
+                    NmpGroup::Image,
+                    NmpIdImage::Erase,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.responses.push_back(encoded_response);
+            }
+            _ => {
+                // Handle other cases or return an error
+            }
+        }
+
+        // add some delay for simulating real transfers
+        // simulating 10 kB/s
+        thread::sleep(Duration::from_millis((data.len() / 10) as u64));
+
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        match self.responses.pop_front() {
+            Some(frame) => Ok(frame),
+            None => Err(SmpError::from(TransportError::Timeout).into()),
+        }
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+//! Fixtures shared by the unit tests.
+
+use std::path::PathBuf;
+
+use crate::session::Session;
+use crate::sim::{SimDevice, SimOptions};
+use crate::transfer::SerialSpecs;
+
+/// Specs of the built-in `test` device, with short timeouts.
+pub(crate) fn test_specs() -> SerialSpecs {
+    SerialSpecs {
+        device: "test".to_string(),
+        initial_timeout_s: 1,
+        subsequent_timeout_ms: 200,
+        nb_retry: 3,
+        linelength: 128,
+        mtu: 512,
+        baudrate: 115_200,
+        window: 1,
+    }
+}
+
+/// Write `content` to a file in the temp directory and return its path.
+pub(crate) fn temp_file(name: &str, content: &[u8]) -> PathBuf {
+    let filename = std::env::temp_dir().join(format!("mcumgr-client-{}", name));
+    std::fs::write(&filename, content).unwrap();
+    filename
+}
+
+/// A session with a simulated device.
+pub(crate) fn sim_session(specs: SerialSpecs, options: SimOptions) -> Session {
+    Session::with_transport(&specs, Box::new(SimDevice::new(options)))
+}
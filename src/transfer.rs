@@ -1,23 +1,24 @@
 // Copyright © 2023-2024 Vouch.io LLC
 
-use anyhow::{bail, Context, Error, Result};
+use anyhow::{Context, Error, Result};
 use base64::{engine::general_purpose, Engine as _};
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use crc16::*;
-use hex;
 use lazy_static::lazy_static;
 use log::debug;
 use rand::{thread_rng, Rng};
-use serde_cbor;
 use serialport::SerialPort;
 use std::cmp::min;
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::sync::atomic::{AtomicU8, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::error::{SmpError, TransportError};
 use crate::nmp_hdr::*;
-use crate::test_serial_port::TestSerialPort;
+use crate::test_transport::TestTransport;
+use crate::udp::{UdpTransport, UDP_PREFIX};
 
+#[derive(Clone)]
 pub struct SerialSpecs {
     pub device: String,
     pub initial_timeout_s: u32,
@@ -25,34 +26,178 @@ pub struct SerialSpecs {
     pub nb_retry: u32,
     pub linelength: usize,
     pub mtu: usize,
-    pub baudrate: u32
+    pub baudrate: u32,
+    pub window: usize,
 }
 
-fn read_byte(port: &mut dyn SerialPort) -> Result<u8, Error> {
-    let mut byte = [0u8];
-    port.read(&mut byte)?;
-    Ok(byte[0])
+/// A link to a device which carries raw SMP frames.
+///
+/// A frame is the 8 byte `NmpHdr` followed by the CBOR body. Any encoding
+/// needed on the wire, like the base64 console framing of the serial port,
+/// is done by the implementation.
+pub trait SmpTransport {
+    /// Send one frame to the device.
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error>;
+
+    /// Wait for the next frame from the device.
+    fn recv(&mut self) -> Result<Vec<u8>, Error>;
+
+    /// Send a request frame and wait for the response frame.
+    fn transceive(&mut self, frame: &[u8]) -> Result<Vec<u8>, Error> {
+        self.send(frame)?;
+        self.recv()
+    }
+
+    /// Set how long `recv` waits for a frame.
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error>;
+
+    /// Number of bytes a frame of `frame_len` bytes needs on the wire.
+    fn encoded_len(&self, frame_len: usize) -> usize {
+        frame_len
+    }
+}
+
+/// SMP over a serial port, using the base64 console framing of the MCUmgr shell transport.
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+    linelength: usize,
+    console: Box<dyn FnMut(&str)>,
+}
+
+impl SerialTransport {
+    pub fn new(port: Box<dyn SerialPort>, linelength: usize) -> SerialTransport {
+        SerialTransport {
+            port,
+            linelength,
+            console: Box::new(log_console),
+        }
+    }
+
+    /// Pass the console output between the SMP frames to `console`, line by line,
+    /// instead of the log.
+    pub fn set_console_handler(&mut self, console: impl FnMut(&str) + 'static) {
+        self.console = Box::new(console);
+    }
+}
+
+impl SmpTransport for SerialTransport {
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+        let data = encode_serial_frame(self.linelength, frame)?;
+        self.port.write_all(&data)?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        // console output must not keep the read going past the timeout
+        let timeout = self.port.timeout();
+        let mut reader = DeadlineReader {
+            port: &mut *self.port,
+            deadline: Instant::now() + timeout,
+        };
+        let result = read_serial_frame_with(&mut reader, &mut *self.console);
+        self.port.set_timeout(timeout)?;
+        result.map_err(|e| match e.downcast_ref::<std::io::Error>() {
+            Some(io) if io.kind() == std::io::ErrorKind::TimedOut => {
+                SmpError::from(TransportError::Timeout).into()
+            }
+            _ => e,
+        })
+    }
+
+    fn transceive(&mut self, frame: &[u8]) -> Result<Vec<u8>, Error> {
+        // empty input buffer, it can only hold console output
+        let to_read = self.port.bytes_to_read()?;
+        let mut pending = vec![0u8; to_read as usize];
+        self.port.read_exact(&mut pending)?;
+        for line in String::from_utf8_lossy(&pending).lines() {
+            if !line.is_empty() {
+                (self.console)(line);
+            }
+        }
+
+        self.send(frame)?;
+        self.recv()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.port.set_timeout(timeout)?;
+        Ok(())
+    }
+
+    fn encoded_len(&self, frame_len: usize) -> usize {
+        // length prefix and checksum, base64 encoded, plus start designator and newline per line
+        let base64_len = (frame_len + 4).div_ceil(3) * 4;
+        let lines = base64_len.div_ceil(self.linelength - 4);
+        base64_len + lines * 3
+    }
+}
+
+/// Reads from a serial port until a deadline, instead of restarting the timeout with
+/// every byte.
+struct DeadlineReader<'a> {
+    port: &'a mut dyn SerialPort,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.port.set_timeout(remaining)?;
+        self.port.read(buf)
+    }
 }
 
-fn expect_byte(port: &mut dyn SerialPort, b: u8) -> Result<(), Error> {
-    let read = read_byte(port)?;
-    if read != b {
-        bail!("read error, expected: {}, read: {}", b, read);
+/// Takes the place of a closed transport, e.g. while the device resets.
+pub(crate) struct ClosedTransport;
+
+impl SmpTransport for ClosedTransport {
+    fn send(&mut self, _frame: &[u8]) -> Result<(), Error> {
+        anyhow::bail!("transport is closed")
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        anyhow::bail!("transport is closed")
     }
-    Ok(())
+
+    fn set_timeout(&mut self, _timeout: Duration) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn read_byte<R: Read + ?Sized>(port: &mut R) -> Result<u8, Error> {
+    let mut byte = [0u8];
+    port.read_exact(&mut byte)?;
+    Ok(byte[0])
 }
 
-pub fn open_port(specs: &SerialSpecs) -> Result<Box<dyn SerialPort>, Error> {
+pub fn open_transport(specs: &SerialSpecs) -> Result<Box<dyn SmpTransport>, Error> {
     if specs.device.to_lowercase() == "test" {
-        Ok(Box::new(TestSerialPort::new()))
+        Ok(Box::new(TestTransport::open()?))
+    } else if let Some(address) = specs.device.strip_prefix(UDP_PREFIX) {
+        Ok(Box::new(UdpTransport::open(
+            address,
+            Duration::from_secs(specs.initial_timeout_s as u64),
+        )?))
     } else {
-        serialport::new(&specs.device, specs.baudrate)
+        let port = serialport::new(&specs.device, specs.baudrate)
             .timeout(Duration::from_secs(specs.initial_timeout_s as u64))
             .open()
-            .with_context(|| format!("failed to open serial port {}", &specs.device))
+            .with_context(|| format!("failed to open serial port {}", &specs.device))?;
+        Ok(Box::new(SerialTransport::new(port, specs.linelength)))
     }
 }
 
+/// True if the error is a timeout while waiting for an answer from the device.
+pub fn is_timeout(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<SmpError>(),
+        Some(SmpError::Transport(TransportError::Timeout))
+    ) || matches!(e.downcast_ref::<std::io::Error>(), Some(e) if e.kind() == std::io::ErrorKind::TimedOut)
+}
+
 // thread-safe counter, initialized with a random value on first call
 pub fn next_seq_id() -> u8 {
     lazy_static! {
@@ -61,16 +206,29 @@ pub fn next_seq_id() -> u8 {
     COUNTER.fetch_add(1, Ordering::SeqCst)
 }
 
-pub fn encode_request(
-    linelength: usize,
+/// Build a raw SMP frame: the request header followed by the CBOR body.
+pub fn encode_frame(
+    op: NmpOp,
+    group: NmpGroup,
+    id: impl NmpId,
+    body: &[u8],
+    seq_id: u8,
+) -> Result<(Vec<u8>, NmpHdr), Error> {
+    encode_versioned_frame(SMP_VERSION_1, op, group, id, body, seq_id)
+}
+
+/// Build a raw SMP frame with the given protocol version in its header.
+pub fn encode_versioned_frame(
+    version: u8,
     op: NmpOp,
     group: NmpGroup,
     id: impl NmpId,
-    body: &Vec<u8>,
+    body: &[u8],
     seq_id: u8,
 ) -> Result<(Vec<u8>, NmpHdr), Error> {
     // create request
     let mut request_header = NmpHdr::new_req(op, group, id);
+    request_header.version = version;
     request_header.seq = seq_id;
     request_header.len = body.len() as u16;
     debug!("request header: {:?}", request_header);
@@ -78,6 +236,43 @@ pub fn encode_request(
     serialized.extend(body);
     debug!("serialized: {}", hex::encode(&serialized));
 
+    Ok((serialized, request_header))
+}
+
+/// Split a raw SMP frame into its header and CBOR body.
+pub fn decode_frame(frame: &[u8]) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    // read header
+    let mut cursor = Cursor::new(frame);
+    let response_header = NmpHdr::deserialize(&mut cursor).map_err(|e| {
+        debug!("invalid SMP header: {}", e);
+        SmpError::from(TransportError::InvalidHeader)
+    })?;
+    debug!("response header: {:?}", response_header);
+
+    // the header announces the body length, which must match what was read
+    let body = &frame[8..];
+    if body.len() != response_header.len as usize {
+        return Err(SmpError::from(TransportError::WrongLength {
+            expected: response_header.len as usize,
+            read: body.len(),
+        })
+        .into());
+    }
+    debug!("cbor: {}", hex::encode(body));
+
+    // decode body in CBOR format
+    let body = serde_cbor::from_slice(body).map_err(|e| {
+        debug!("invalid CBOR body: {}", e);
+        SmpError::from(TransportError::InvalidBody)
+    })?;
+
+    Ok((response_header, body))
+}
+
+/// Wrap a raw SMP frame in the base64 console framing used on serial ports.
+pub fn encode_serial_frame(linelength: usize, frame: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut serialized = frame.to_vec();
+
     // calculate CRC16 of it and append to the request
     let checksum = State::<XMODEM>::calculate(&serialized);
     serialized.write_u16::<BigEndian>(checksum)?;
@@ -114,97 +309,235 @@ pub fn encode_request(
         written += write_len;
     }
 
-    Ok((data, request_header))
+    Ok(data)
 }
 
-pub fn transceive(
-    port: &mut dyn SerialPort,
-    data: &Vec<u8>,
-) -> Result<(NmpHdr, serde_cbor::Value), Error> {
-    // empty input buffer
-    let to_read = port.bytes_to_read()?;
-    for _ in 0..to_read {
-        read_byte(&mut *port)?;
-    }
-
-    // write request
-    port.write_all(data)?;
+/// Read one base64 console frame and return the raw SMP frame it carries.
+///
+/// Console output in front of the frame is skipped and passed to the log.
+pub fn read_serial_frame<R: Read + ?Sized>(port: &mut R) -> Result<Vec<u8>, Error> {
+    read_serial_frame_with(port, &mut log_console)
+}
 
-    // read result
-    let mut bytes_read = 0;
-    let mut expected_len = 0;
-    let mut result: Vec<u8> = Vec::new();
+/// Read one base64 console frame, passing the console output around it to `console`.
+///
+/// The frame start marker is searched in the stream, so the SMP frames can share the
+/// port with a shell or log output. A partial or corrupt frame is dropped and the
+/// next one is read. If no valid frame follows, the reason for the drop is returned
+/// instead of the read error.
+pub fn read_serial_frame_with<R: Read + ?Sized>(
+    port: &mut R,
+    console: &mut dyn FnMut(&str),
+) -> Result<Vec<u8>, Error> {
+    let mut scanner = FrameScanner::new(console);
     loop {
-        // first wait for the chunk start marker
-        if bytes_read == 0 {
-            expect_byte(&mut *port, 6)?;
-            expect_byte(&mut *port, 9)?;
-        } else {
-            expect_byte(&mut *port, 4)?;
-            expect_byte(&mut *port, 20)?;
-        }
-
-        // next read until newline
-        loop {
-            let b = read_byte(&mut *port)?;
-            if b == 0xa {
-                break;
-            } else {
-                result.push(b);
-                bytes_read += 1;
+        let b = match read_byte(&mut *port) {
+            Ok(b) => b,
+            Err(e) => {
+                scanner.flush_console();
+                return Err(scanner.dropped.unwrap_or(e));
             }
+        };
+        if let Some(frame) = scanner.push(b) {
+            scanner.flush_console();
+            return Ok(frame);
         }
+    }
+}
 
-        // try to extract length
-        let decoded: Vec<u8> = general_purpose::STANDARD.decode(&result)?;
-        if expected_len == 0 {
-            let len = BigEndian::read_u16(&decoded);
-            if len > 0 {
-                expected_len = len as usize;
-            }
-            debug!("expected length: {}", expected_len);
-        }
+/// Default console handler, which logs each line.
+pub fn log_console(line: &str) {
+    debug!("console: {}", line);
+}
+
+enum ScanState {
+    /// Console output, waiting for a frame start marker
+    Console,
+    /// Inside a base64 line of a frame
+    Line,
+    /// After a frame line, waiting for the continuation marker
+    Continuation,
+    /// Console output after a dropped frame, whose continuation lines are skipped
+    Dropped,
+    /// Inside a continuation line of a dropped frame
+    Skip,
+}
 
-        // stop when done
-        if (decoded.len() - 2) >= expected_len {
-            break;
+/// Splits a serial byte stream into console lines and SMP frames.
+struct FrameScanner<'a> {
+    console: &'a mut dyn FnMut(&str),
+    state: ScanState,
+    text: Vec<u8>,
+    base64: Vec<u8>,
+    // first byte of a possible marker
+    marker: Option<u8>,
+    dropped: Option<Error>,
+}
+
+impl<'a> FrameScanner<'a> {
+    fn new(console: &'a mut dyn FnMut(&str)) -> FrameScanner<'a> {
+        FrameScanner {
+            console,
+            state: ScanState::Console,
+            text: Vec::new(),
+            base64: Vec::new(),
+            marker: None,
+            dropped: None,
         }
     }
 
-    // decode base64
-    debug!("result string: {}", String::from_utf8(result.clone())?);
-    let decoded: Vec<u8> = general_purpose::STANDARD.decode(&result)?;
+    /// Process the next byte, returns the raw SMP frame when it is complete.
+    fn push(&mut self, b: u8) -> Option<Vec<u8>> {
+        // a frame starts with 6 9 anywhere in the stream, its lines continue with 4 20
+        if let Some(first) = self.marker.take() {
+            match (first, b) {
+                (6, 9) => {
+                    self.start_frame();
+                    return None;
+                }
+                (4, 20) => {
+                    self.state = match self.state {
+                        ScanState::Dropped => ScanState::Skip,
+                        _ => ScanState::Line,
+                    };
+                    return None;
+                }
+                // not a marker, which ends a frame, so this can't complete one
+                _ => {
+                    self.byte(first);
+                }
+            }
+        }
+        if b == 6 || (b == 4 && matches!(self.state, ScanState::Continuation | ScanState::Dropped))
+        {
+            self.marker = Some(b);
+            return None;
+        }
+        self.byte(b)
+    }
 
-    // verify length: must be the decoded length, minus the 2 bytes to encode the length
-    let len = BigEndian::read_u16(&decoded) as usize;
-    if len != decoded.len() - 2 {
-        bail!("wrong chunk length");
+    fn byte(&mut self, b: u8) -> Option<Vec<u8>> {
+        match self.state {
+            ScanState::Console | ScanState::Dropped => {
+                if b == b'\n' {
+                    self.flush_console();
+                } else {
+                    self.text.push(b);
+                }
+                None
+            }
+            ScanState::Skip => {
+                if b == b'\n' {
+                    self.state = ScanState::Dropped;
+                }
+                None
+            }
+            ScanState::Line if b == b'\n' => self.end_line(),
+            ScanState::Line if b == b'\r' => None,
+            ScanState::Line if b.is_ascii_alphanumeric() || b"+/=".contains(&b) => {
+                self.base64.push(b);
+                None
+            }
+            ScanState::Line | ScanState::Continuation => {
+                // console output in the middle of a frame
+                self.drop_frame(anyhow::format_err!("incomplete frame"));
+                self.byte(b)
+            }
+        }
     }
 
-    // verify checksum
-    let data = decoded[2..decoded.len() - 2].to_vec();
-    let read_checksum = BigEndian::read_u16(&decoded[decoded.len() - 2..]);
-    let calculated_checksum = State::<XMODEM>::calculate(&data);
-    if read_checksum != calculated_checksum {
-        bail!("wrong checksum");
+    fn start_frame(&mut self) {
+        if matches!(self.state, ScanState::Line | ScanState::Continuation) {
+            self.drop_frame(anyhow::format_err!("incomplete frame"));
+        }
+        self.flush_console();
+        self.state = ScanState::Line;
     }
 
-    // read header
-    let mut cursor = Cursor::new(&data);
-    let response_header = NmpHdr::deserialize(&mut cursor).unwrap();
-    debug!("response header: {:?}", response_header);
+    fn end_line(&mut self) -> Option<Vec<u8>> {
+        // lines can end in the middle of a base64 group
+        if !self.base64.len().is_multiple_of(4) {
+            self.state = ScanState::Continuation;
+            return None;
+        }
+        let decoded = match general_purpose::STANDARD.decode(&self.base64) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self.drop_frame(e.into());
+                return None;
+            }
+        };
+        if decoded.len() < 4 {
+            // not even room for the length and the checksum
+            self.drop_frame(
+                SmpError::from(TransportError::WrongLength {
+                    expected: 4,
+                    read: decoded.len(),
+                })
+                .into(),
+            );
+            return None;
+        }
+
+        // the length counts the data and the checksum, but not itself
+        let len = BigEndian::read_u16(&decoded) as usize;
+        debug!("expected length: {}", len);
+        if decoded.len() - 2 < len {
+            self.state = ScanState::Continuation;
+            return None;
+        }
+        if decoded.len() - 2 > len {
+            self.drop_frame(
+                SmpError::from(TransportError::WrongLength {
+                    expected: len,
+                    read: decoded.len() - 2,
+                })
+                .into(),
+            );
+            return None;
+        }
 
-    debug!("cbor: {}", hex::encode(&data[8..]));
+        // verify checksum
+        let data = decoded[2..decoded.len() - 2].to_vec();
+        let read_checksum = BigEndian::read_u16(&decoded[decoded.len() - 2..]);
+        let calculated_checksum = State::<XMODEM>::calculate(&data);
+        if read_checksum != calculated_checksum {
+            self.drop_frame(
+                SmpError::from(TransportError::BadCrc {
+                    calculated: calculated_checksum,
+                    read: read_checksum,
+                })
+                .into(),
+            );
+            return None;
+        }
 
-    // decode body in CBOR format
-    let body = serde_cbor::from_reader(cursor)?;
+        self.state = ScanState::Console;
+        self.base64.clear();
+        self.dropped = None;
+        Some(data)
+    }
 
-    Ok((response_header, body))
+    fn drop_frame(&mut self, e: Error) {
+        debug!("dropping frame: {}", e);
+        self.dropped = Some(e);
+        self.base64.clear();
+        self.state = ScanState::Dropped;
+    }
+
+    fn flush_console(&mut self) {
+        let text = String::from_utf8_lossy(&self.text);
+        let line = text.trim_end_matches('\r');
+        if !line.is_empty() {
+            (self.console)(line);
+        }
+        self.text.clear();
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::next_seq_id;
+    use super::*;
     use std::collections::HashSet;
 
     #[test]
@@ -213,7 +546,7 @@ mod tests {
         let initial_id = next_seq_id();
         ids.insert(initial_id);
 
-        for _ in 0..std::u8::MAX {
+        for _ in 0..u8::MAX {
             let id = next_seq_id();
             assert!(ids.insert(id), "Duplicate ID: {}", id);
         }
@@ -225,4 +558,151 @@ mod tests {
             "Wrapped ID does not match initial ID"
         );
     }
+
+    #[test]
+    fn serial_frame_roundtrip() {
+        let frame: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let encoded = encode_serial_frame(128, &frame).unwrap();
+        let decoded = read_serial_frame(&mut encoded.as_slice()).unwrap();
+        assert_eq!(decoded, frame);
+    }
+
+    #[test]
+    fn serial_frame_bad_crc() {
+        let mut encoded = encode_serial_frame(128, &[1, 2, 3, 4]).unwrap();
+        // flip the payload, keeping valid base64: "AAYBAgME..." becomes "AAYBAgMF..."
+        let pos = encoded.iter().position(|b| *b == b'E').unwrap();
+        encoded[pos] = b'F';
+        let e = read_serial_frame(&mut encoded.as_slice()).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<SmpError>(),
+            Some(SmpError::Transport(TransportError::BadCrc { .. }))
+        ));
+    }
+
+    #[test]
+    fn decode_unknown_group() {
+        let (frame, _) = encode_frame(
+            NmpOp::ReadRsp,
+            NmpGroup::PerUser(63),
+            NmpIdDef::Echo,
+            &[0xa0],
+            7,
+        )
+        .unwrap();
+        assert_eq!(&frame[4..6], &[0, 63]);
+        let (header, body) = decode_frame(&frame).unwrap();
+        assert_eq!(header.group, NmpGroup::PerUser(63));
+        assert_eq!(header.seq, 7);
+        assert_eq!(body, serde_cbor::Value::Map(Default::default()));
+    }
+
+    #[test]
+    fn decode_malformed_frames() {
+        let (frame, _) = encode_frame(
+            NmpOp::ReadRsp,
+            NmpGroup::Image,
+            NmpIdImage::State,
+            &[0xa0],
+            1,
+        )
+        .unwrap();
+
+        let invalid = |frame: &[u8], expected: TransportError| {
+            let e = decode_frame(frame).unwrap_err();
+            assert_eq!(
+                e.downcast_ref::<SmpError>(),
+                Some(&SmpError::Transport(expected))
+            );
+        };
+
+        // truncated header
+        invalid(&frame[..5], TransportError::InvalidHeader);
+        invalid(&[], TransportError::InvalidHeader);
+
+        // unknown op
+        let mut bad_op = frame.clone();
+        bad_op[0] |= 0x07;
+        invalid(&bad_op, TransportError::InvalidHeader);
+
+        // body which is no CBOR
+        let mut bad_body = frame.clone();
+        bad_body[8] = 0xff;
+        invalid(&bad_body, TransportError::InvalidBody);
+
+        // body shorter than announced
+        let e = decode_frame(&frame[..8]).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<SmpError>(),
+            Some(SmpError::Transport(TransportError::WrongLength {
+                expected: 1,
+                read: 0
+            }))
+        ));
+    }
+
+    #[test]
+    fn serial_frame_after_console_output() {
+        let frame: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut stream = b"uart:~$ [00:00:01.000,000] <inf> app: booting\r\nhello".to_vec();
+        stream.extend(encode_serial_frame(64, &frame).unwrap());
+
+        let mut lines = Vec::new();
+        let decoded = read_serial_frame_with(&mut stream.as_slice(), &mut |line: &str| {
+            lines.push(line.to_string())
+        })
+        .unwrap();
+        assert_eq!(decoded, frame);
+        assert_eq!(
+            lines,
+            vec!["uart:~$ [00:00:01.000,000] <inf> app: booting", "hello"]
+        );
+    }
+
+    #[test]
+    fn serial_frame_resync_after_broken_frame() {
+        let first: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let second = vec![1, 2, 3, 4];
+        let encoded = encode_serial_frame(64, &first).unwrap();
+
+        // a log line interrupts the first frame after its first line
+        let split = encoded.iter().position(|b| *b == b'\n').unwrap() + 1;
+        let mut stream = encoded[..split].to_vec();
+        stream.extend(b"<wrn> dropped\n");
+        stream.extend(&encoded[split..]);
+        stream.extend(encode_serial_frame(64, &second).unwrap());
+
+        let mut lines = Vec::new();
+        let decoded = read_serial_frame_with(&mut stream.as_slice(), &mut |line: &str| {
+            lines.push(line.to_string())
+        })
+        .unwrap();
+        assert_eq!(decoded, second);
+        assert_eq!(lines, vec!["<wrn> dropped"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn console_output_does_not_extend_timeout() {
+        use serialport::TTYPort;
+        use std::io::Write;
+        use std::thread;
+
+        let (mut device, host) = TTYPort::pair().unwrap();
+        let writer = thread::spawn(move || {
+            for _ in 0..50 {
+                device.write_all(b"<inf> app: tick\n").unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let mut transport = SerialTransport::new(Box::new(host), 128);
+        transport.set_console_handler(|_| ());
+        transport.set_timeout(Duration::from_millis(200)).unwrap();
+        let start = Instant::now();
+        let e = transport.recv().unwrap_err();
+        assert!(is_timeout(&e));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        writer.join().unwrap();
+    }
 }
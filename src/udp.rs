@@ -0,0 +1,222 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, Context, Error, Result};
+use log::debug;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use crate::error::{SmpError, TransportError};
+use crate::transfer::SmpTransport;
+
+/// Prefix of device names which select the UDP transport, e.g. `udp://192.168.1.10:1337`.
+pub const UDP_PREFIX: &str = "udp://";
+
+/// Default port of the SMP UDP transport in Zephyr.
+pub const UDP_DEFAULT_PORT: u16 = 1337;
+
+/// SMP over UDP: every datagram carries exactly one raw SMP frame, without base64 or CRC.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn open(address: &str, timeout: Duration) -> Result<UdpTransport, Error> {
+        let (host, port) = parse_address(address)?;
+        let peer: SocketAddr = (host.as_str(), port)
+            .to_socket_addrs()
+            .with_context(|| format!("failed to resolve {}", address))?
+            .next()
+            .with_context(|| format!("no address found for {}", address))?;
+        let local: SocketAddr = if peer.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket
+            .connect(peer)
+            .with_context(|| format!("failed to connect to {}", peer))?;
+        socket.set_read_timeout(Some(timeout))?;
+        debug!("udp transport connected to {}", peer);
+        Ok(UdpTransport { socket })
+    }
+}
+
+/// Split `host`, `host:port`, `[ipv6]` or `[ipv6]:port` into host and port, with the
+/// default port if none is given. An IPv6 address without brackets has no port.
+fn parse_address(address: &str) -> Result<(String, u16), Error> {
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let Some((host, rest)) = rest.split_once(']') else {
+            bail!("missing ] in address {}", address);
+        };
+        match rest {
+            "" => (host, None),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => bail!("invalid address {}", address),
+            },
+        }
+    } else if address.matches(':').count() > 1 {
+        (address, None)
+    } else {
+        match address.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        }
+    };
+    if host.is_empty() {
+        bail!("missing host in address {}", address);
+    }
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .with_context(|| format!("invalid port in address {}", address))?,
+        None => UDP_DEFAULT_PORT,
+    };
+    Ok((host.to_string(), port))
+}
+
+impl SmpTransport for UdpTransport {
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.socket.send(frame)?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0u8; 65535];
+        match self.socket.recv(&mut buf) {
+            Ok(len) => {
+                buf.truncate(len);
+                Ok(buf)
+            }
+            // report timeouts the same way as the serial port, so retries work alike
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Err(SmpError::from(TransportError::Timeout).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::UploadOptions;
+    use crate::mcuboot::tests::test_image;
+    use crate::nmp_hdr::*;
+    use crate::session::Session;
+    use crate::sim::{SimDevice, SimOptions};
+    use crate::test_util::test_specs;
+    use crate::transfer::{decode_frame, encode_frame, SerialSpecs};
+    use std::thread;
+
+    #[test]
+    fn list_over_udp() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = responder.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            let (len, peer) = responder.recv_from(&mut buf).unwrap();
+            let (header, _) = decode_frame(&buf[..len]).unwrap();
+            let body = serde_cbor::to_vec(&ImageStateRsp {
+                images: Vec::new(),
+                split_status: None,
+            })
+            .unwrap();
+            let (frame, _) = encode_frame(
+                NmpOp::ReadRsp,
+                NmpGroup::Image,
+                NmpIdImage::State,
+                &body,
+                header.seq,
+            )
+            .unwrap();
+            responder.send_to(&frame, peer).unwrap();
+        });
+
+        let specs = SerialSpecs {
+            device: format!("{}{}", UDP_PREFIX, address),
+            initial_timeout_s: 5,
+            ..test_specs()
+        };
+        let mut session = Session::open(&specs).unwrap();
+        let state = session.list().unwrap();
+        assert!(state.images.is_empty());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn parse_addresses() {
+        let parse = |address: &str| parse_address(address).unwrap();
+        assert_eq!(parse("192.168.1.10"), ("192.168.1.10".to_string(), 1337));
+        assert_eq!(
+            parse("device.local:5683"),
+            ("device.local".to_string(), 5683)
+        );
+        assert_eq!(parse("[::1]"), ("::1".to_string(), 1337));
+        assert_eq!(parse("[fe80::1]:1338"), ("fe80::1".to_string(), 1338));
+        assert_eq!(parse("2001:db8::1"), ("2001:db8::1".to_string(), 1337));
+        for bad in ["[::1", "[::1]1337", "host:port", "host:70000", ":1337", ""] {
+            assert!(parse_address(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn open_ipv6() {
+        // hosts without IPv6 can't run this test
+        let Ok(responder) = UdpSocket::bind("[::1]:0") else {
+            return;
+        };
+        let port = responder.local_addr().unwrap().port();
+        let mut transport =
+            UdpTransport::open(&format!("[::1]:{}", port), Duration::from_secs(5)).unwrap();
+        transport.send(&[1, 2, 3]).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, peer) = responder.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+        responder.send_to(&[4, 5], peer).unwrap();
+        assert_eq!(transport.recv().unwrap(), vec![4, 5]);
+    }
+
+    #[test]
+    fn late_answers_are_skipped() {
+        // answers come after the timeout, so every request is sent again and the answers
+        // to the first attempts arrive while the next request waits
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let specs = SerialSpecs {
+            device: format!("{}{}", UDP_PREFIX, socket.local_addr().unwrap()),
+            subsequent_timeout_ms: 100,
+            nb_retry: 10,
+            ..test_specs()
+        };
+        thread::spawn(move || {
+            SimDevice::new(SimOptions {
+                latency: Duration::from_millis(150),
+                ..Default::default()
+            })
+            .serve_udp(&socket)
+        });
+
+        let image = test_image(&[0x5a; 3000], &[0x44; 32]);
+        let mut session = Session::open(&specs).unwrap();
+        session
+            .upload_image(
+                &image,
+                0,
+                &UploadOptions::default(),
+                &mut None::<fn(u64, u64)>,
+            )
+            .unwrap();
+        assert_eq!(session.echo("after").unwrap(), "after");
+        let state = session.list().unwrap();
+        assert_eq!(state.images[0].hash, vec![0x44; 32]);
+    }
+}